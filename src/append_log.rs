@@ -0,0 +1,325 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use gix::object::Kind;
+use gix::prelude::Find;
+use gix::{Commit, Repository, Tree};
+use gix_hash::ObjectId;
+use gix_object::tree::EntryMode;
+
+use crate::kv_ledger::{apply, read_tree};
+use crate::{GitLedger, LedgerError, LedgerResult};
+
+/// Bits of the sequence number consumed by each level of the fan-out, from
+/// the leaf up. The root level takes the remaining high bits.
+const FANOUT_BITS: u32 = 8;
+const LEVELS: usize = 3;
+
+/// `GitLedger` holding an append-only sequence of entries. Entry `n` is a blob
+/// at a fan-out path derived from `n`, such as `000000000000/01/2c` for 300,
+/// so each append rewrites only the trees along one path.
+///
+/// Every fetch checks that each commit since the last one verified through
+/// this handle or any clone of it, or since the first commit for a new handle,
+/// only appends entries to its parent, and that entries seen by an earlier
+/// fetch are still present and unchanged. Commits before the boundary of a
+/// shallow fetch cannot be checked.
+#[derive(Clone, Debug)]
+pub struct AppendLog {
+    inner: GitLedger,
+    verified: Arc<Mutex<Option<ObjectId>>>,
+}
+
+impl AppendLog {
+    pub fn new(inner: GitLedger) -> AppendLog {
+        AppendLog {
+            inner,
+            verified: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn inner(&self) -> &GitLedger {
+        &self.inner
+    }
+
+    /// Append `data`, returning its sequence number.
+    pub fn append(&self, data: &[u8]) -> LedgerResult<u64> {
+        let mut seq = 0;
+        let commit = self
+            .inner
+            .update_with(|repo, old| {
+                let root = self.verify(old)?;
+                seq = len(repo, root)?;
+                apply(repo, root, |txn| {
+                    txn.put(&path(seq).join("/"), data);
+                    Ok::<_, std::convert::Infallible>(())
+                })
+            })
+            .map_err(LedgerError::unwrap_update)?;
+
+        self.mark_verified(commit);
+        Ok(seq)
+    }
+
+    /// The number of entries upstream.
+    pub fn len(&self) -> LedgerResult<u64> {
+        let root = self.verify(self.inner.fetch()?)?;
+        len(&self.inner.repo, root)
+    }
+
+    pub fn is_empty(&self) -> LedgerResult<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// All upstream entries from sequence number `seq` onwards.
+    pub fn read_from(&self, seq: u64) -> LedgerResult<Vec<Vec<u8>>> {
+        let mut entries = Vec::new();
+        if let Some(root) = self.verify(self.inner.fetch()?)? {
+            collect(&self.inner.repo, root, 0, 0, seq, &mut entries)?;
+        }
+        Ok(entries)
+    }
+
+    /// Check the fetched state, and each commit leading to it, against the
+    /// last verified one, returning its root tree.
+    fn verify(&self, state: Option<(Commit<'_>, Tree<'_>)>) -> LedgerResult<Option<ObjectId>> {
+        let repo = &self.inner.repo;
+        let verified = *self.verified.lock().unwrap();
+        let Some((commit, tree)) = state else {
+            return match verified {
+                None => Ok(None),
+                Some(..) => Err(LedgerError::CorruptLedger(
+                    "append log was deleted".to_string(),
+                )),
+            };
+        };
+        let (commit, root) = (commit.id, tree.id);
+        // The upstream history may have been replaced wholesale.
+        if let Some(verified) = verified {
+            unchanged(repo, commit_tree(repo, verified)?, root, 0, 0)?;
+        }
+        only_appends(repo, commit, verified)?;
+        self.mark_verified(commit);
+        Ok(Some(root))
+    }
+
+    fn mark_verified(&self, commit: ObjectId) {
+        *self.verified.lock().unwrap() = Some(commit);
+    }
+}
+
+/// Path components of entry `seq`.
+fn path(seq: u64) -> Vec<String> {
+    (0..LEVELS)
+        .map(|level| match level {
+            0 => format!("{:012x}", seq >> shift(level)),
+            level => format!("{:02x}", (seq >> shift(level)) & 0xff),
+        })
+        .collect()
+}
+
+fn shift(level: usize) -> u32 {
+    FANOUT_BITS * (LEVELS - 1 - level) as u32
+}
+
+/// Check that every commit reachable from `tip` but not from `verified` only
+/// appends to each of its parents. Parents missing from a shallow repository
+/// are skipped.
+fn only_appends(repo: &Repository, tip: ObjectId, verified: Option<ObjectId>) -> LedgerResult<()> {
+    let mut pending = vec![tip];
+    let mut seen = HashSet::new();
+    while let Some(id) = pending.pop() {
+        if Some(id) == verified || !seen.insert(id) {
+            continue;
+        }
+        let commit = repo
+            .find_object(id)
+            .context("find commit")?
+            .try_into_commit()
+            .context("expected commit")?;
+        let tree = commit.tree_id().context("commit tree")?.detach();
+        for parent in commit.parent_ids() {
+            let parent = parent.detach();
+            if !repo.objects.contains(parent) {
+                continue;
+            }
+            unchanged(repo, commit_tree(repo, parent)?, tree, 0, 0)?;
+            pending.push(parent);
+        }
+    }
+    Ok(())
+}
+
+fn commit_tree(repo: &Repository, commit: ObjectId) -> LedgerResult<ObjectId> {
+    Ok(repo
+        .find_object(commit)
+        .context("find commit")?
+        .try_into_commit()
+        .context("expected commit")?
+        .tree_id()
+        .context("commit tree")?
+        .detach())
+}
+
+/// Check that every entry under `old` is unchanged under `new`. Trees that
+/// hash the same are skipped, so only the most recently appended paths are
+/// read.
+fn unchanged(
+    repo: &Repository,
+    old: ObjectId,
+    new: ObjectId,
+    level: usize,
+    base: u64,
+) -> LedgerResult<()> {
+    if old == new {
+        return Ok(());
+    }
+    let new_entries = read_tree(repo, new)?.entries;
+    for entry in read_tree(repo, old)?.entries {
+        let base = base | (parse_component(&entry.filename.to_string(), level)? << shift(level));
+        let modified =
+            || LedgerError::CorruptLedger(format!("append log entry {} was modified", base));
+        let new_entry = new_entries
+            .iter()
+            .find(|new_entry| new_entry.filename == entry.filename)
+            .ok_or_else(modified)?;
+        if new_entry.oid == entry.oid {
+            continue;
+        }
+        if level + 1 == LEVELS || new_entry.mode != EntryMode::Tree {
+            return Err(modified());
+        }
+        unchanged(repo, entry.oid, new_entry.oid, level + 1, base)?;
+    }
+    Ok(())
+}
+
+/// One more than the highest sequence number, found by following the last
+/// entry at each level.
+fn len(repo: &Repository, root: Option<ObjectId>) -> LedgerResult<u64> {
+    let mut tree = match root {
+        Some(root) => root,
+        None => return Ok(0),
+    };
+    let mut seq = 0;
+    for level in 0..LEVELS {
+        let entry = match read_tree(repo, tree)?.entries.pop() {
+            Some(entry) => entry,
+            None if level == 0 => return Ok(0),
+            None => return Err(LedgerError::CorruptLedger("empty directory".to_string())),
+        };
+        seq |= parse_component(&entry.filename.to_string(), level)? << shift(level);
+        tree = entry.oid;
+    }
+    Ok(seq + 1)
+}
+
+fn collect(
+    repo: &Repository,
+    tree: ObjectId,
+    level: usize,
+    base: u64,
+    from: u64,
+    entries: &mut Vec<Vec<u8>>,
+) -> LedgerResult<()> {
+    for entry in read_tree(repo, tree)?.entries {
+        let base = base | (parse_component(&entry.filename.to_string(), level)? << shift(level));
+        let last = base | ((1 << shift(level)) - 1);
+        if last < from {
+            continue;
+        }
+        if level + 1 < LEVELS {
+            collect(repo, entry.oid, level + 1, base, from, entries)?;
+            continue;
+        }
+        let blob = repo.find_object(entry.oid).context("find blob")?;
+        if blob.kind != Kind::Blob {
+            return Err(LedgerError::CorruptLedger("not a blob".to_string()));
+        }
+        entries.push(blob.data.to_vec());
+    }
+    Ok(())
+}
+
+fn parse_component(name: &str, level: usize) -> LedgerResult<u64> {
+    let width = if level == 0 { 12 } else { 2 };
+    match u64::from_str_radix(name, 16) {
+        Ok(component) if name.len() == width => Ok(component),
+        _ => Err(LedgerError::CorruptLedger(format!(
+            "unexpected append log entry {:?}",
+            name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+
+    fn make_log(path: &std::path::Path, j: usize) -> AppendLog {
+        AppendLog::new(testing::ledger(path, j))
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(path(0), ["000000000000", "00", "00"]);
+        assert_eq!(path(300), ["000000000000", "01", "2c"]);
+        assert_eq!(path(0x1234567), ["000000000123", "45", "67"]);
+    }
+
+    #[test]
+    fn test_append_log() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let log1 = make_log(tmp.path(), 1);
+        let log2 = make_log(tmp.path(), 2);
+        assert!(log1.is_empty().unwrap());
+        assert!(log1.read_from(0).unwrap().is_empty());
+
+        for i in 0..5u64 {
+            let log = if i % 2 == 0 { &log1 } else { &log2 };
+            assert_eq!(log.append(i.to_string().as_bytes()).unwrap(), i);
+        }
+
+        assert_eq!(log2.len().unwrap(), 5);
+        assert_eq!(
+            log1.read_from(2).unwrap(),
+            [b"2".to_vec(), b"3".to_vec(), b"4".to_vec()]
+        );
+        assert!(log1.read_from(5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_modified_entry() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let log = make_log(tmp.path(), 1);
+        log.append(b"0").unwrap();
+        log.append(b"1").unwrap();
+
+        // Someone with push access replaces entry 0.
+        let inner = make_log(tmp.path(), 2).inner;
+        inner
+            .update_with(|repo, _| {
+                apply(repo, None, |txn| {
+                    txn.put(&path(0).join("/"), "forged");
+                    txn.put(&path(1).join("/"), "1");
+                    Ok::<_, std::convert::Infallible>(())
+                })
+            })
+            .unwrap();
+
+        assert!(matches!(log.len(), Err(LedgerError::CorruptLedger(..))));
+        assert!(matches!(
+            log.append(b"2"),
+            Err(LedgerError::CorruptLedger(..))
+        ));
+
+        // A fresh handle checks the history it fetched.
+        assert!(matches!(
+            make_log(tmp.path(), 3).read_from(0),
+            Err(LedgerError::CorruptLedger(..))
+        ));
+    }
+}
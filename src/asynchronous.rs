@@ -0,0 +1,437 @@
+use std::future::Future;
+use std::time::Instant;
+
+use gix::{Commit, Repository};
+use gix_hash::ObjectId;
+use gix_object::Tree as TreeBuilder;
+
+use crate::{
+    BlobGitLedger, BlobGitLedgerGuard, CommitInfo, Freshness, GitLedger, LedgerError, LedgerResult,
+    PushOutcome, Snapshot,
+};
+
+/// Async front end to `GitLedger`, for use from tokio. gix can only fetch and
+/// push with its blocking client alongside the transports this crate enables,
+/// so this is not non-blocking I/O: each fetch, push and whole
+/// fetch-update-push attempt of `update_[once_]with` runs on tokio's blocking
+/// pool, holding one of its threads until done, and only waits between
+/// attempts yield the task. `fetch` and `fetch_with` read the returned state
+/// from the local object database on the calling task, as it borrows the
+/// ledger.
+///
+/// Methods take `&mut self` so that their futures are `Send`; clone the ledger
+/// to use it from several tasks at once. Dropping a future cancels the
+/// operation at its next await point, but work already handed to the
+/// blocking pool, including a call to an update function, runs to completion
+/// in the background.
+#[derive(Clone, Debug)]
+pub struct AsyncGitLedger {
+    inner: GitLedger,
+}
+
+impl AsyncGitLedger {
+    pub fn new(inner: GitLedger) -> AsyncGitLedger {
+        AsyncGitLedger { inner }
+    }
+
+    /// The underlying blocking ledger.
+    pub fn inner(&self) -> &GitLedger {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> GitLedger {
+        self.inner
+    }
+
+    pub async fn fetch(&mut self) -> LedgerResult<Option<(Commit<'_>, gix::Tree<'_>)>> {
+        fetch_refs(&self.inner).await?;
+        self.inner.read_branch()
+    }
+
+    pub async fn fetch_with(&mut self, freshness: Freshness) -> LedgerResult<Snapshot<'_>> {
+        if self.inner.needs_fetch(freshness) {
+            fetch_refs(&self.inner).await?;
+        }
+        self.inner.snapshot()
+    }
+
+    pub async fn push(
+        &mut self,
+        old_commit_id: Option<ObjectId>,
+        tree: &TreeBuilder,
+    ) -> LedgerResult<PushOutcome> {
+        let info = self.inner.commit_info().clone();
+        self.push_with_info(old_commit_id, tree, &info).await
+    }
+
+    /// As `push`, describing the commit with `info`.
+    pub async fn push_with_info(
+        &mut self,
+        old_commit_id: Option<ObjectId>,
+        tree: &TreeBuilder,
+        info: &CommitInfo,
+    ) -> LedgerResult<PushOutcome> {
+        let ledger = self.inner.clone();
+        let tree = tree.clone();
+        let info = info.clone();
+        blocking(move || ledger.push_with_info(old_commit_id, &tree, &info)).await?
+    }
+
+    /// As `GitLedger::update_once_with`, running the fetch, `f` and the push
+    /// together on the blocking pool.
+    pub async fn update_once_with<F, E>(&mut self, f: F) -> LedgerResult<PushOutcome>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnOnce(
+                &Repository,
+                Option<(Commit<'_>, gix::Tree<'_>)>,
+            ) -> std::result::Result<TreeBuilder, E>
+            + Send
+            + 'static,
+    {
+        let info = self.inner.commit_info().clone();
+        self.update_once_with_info(&info, f).await
+    }
+
+    /// As `update_once_with`, describing the commit with `info`.
+    pub async fn update_once_with_info<F, E>(
+        &mut self,
+        info: &CommitInfo,
+        f: F,
+    ) -> LedgerResult<PushOutcome>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnOnce(
+                &Repository,
+                Option<(Commit<'_>, gix::Tree<'_>)>,
+            ) -> std::result::Result<TreeBuilder, E>
+            + Send
+            + 'static,
+    {
+        let ledger = self.inner.clone();
+        let info = info.clone();
+        blocking(move || ledger.update_once_with_info(&info, f)).await?
+    }
+
+    /// Repeatedly apply `f` to the latest upstream state until a push
+    /// succeeds, returning the new commit. Each attempt runs on the blocking
+    /// pool, and the task sleeps between attempts.
+    pub async fn update_with<F, E>(&mut self, f: F) -> LedgerResult<ObjectId>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnMut(
+                &Repository,
+                Option<(Commit<'_>, gix::Tree<'_>)>,
+            ) -> std::result::Result<TreeBuilder, E>
+            + Send
+            + 'static,
+    {
+        let info = self.inner.commit_info().clone();
+        self.update_with_info(&info, f).await
+    }
+
+    /// As `update_with`, describing the commit with `info`.
+    pub async fn update_with_info<F, E>(
+        &mut self,
+        info: &CommitInfo,
+        mut f: F,
+    ) -> LedgerResult<ObjectId>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnMut(
+                &Repository,
+                Option<(Commit<'_>, gix::Tree<'_>)>,
+            ) -> std::result::Result<TreeBuilder, E>
+            + Send
+            + 'static,
+    {
+        let policy = self.inner.retry_policy().clone();
+        let mut retry = policy.start();
+        loop {
+            let ledger = self.inner.clone();
+            let attempt_info = info.clone();
+            let (returned, outcome) = blocking(move || {
+                let outcome = ledger.update_once_with_info(&attempt_info, &mut f);
+                (f, outcome)
+            })
+            .await?;
+            f = returned;
+            if let PushOutcome::Committed(id) = outcome? {
+                return Ok(id);
+            }
+            tokio::time::sleep(retry.failed()?).await;
+        }
+    }
+}
+
+impl From<GitLedger> for AsyncGitLedger {
+    fn from(inner: GitLedger) -> AsyncGitLedger {
+        AsyncGitLedger::new(inner)
+    }
+}
+
+impl From<BlobGitLedger> for AsyncBlobGitLedger {
+    fn from(inner: BlobGitLedger) -> AsyncBlobGitLedger {
+        AsyncBlobGitLedger::new(inner)
+    }
+}
+
+/// Async front end to `BlobGitLedger`. Waiting for a lease sleeps the task
+/// rather than the thread, while each poll of the lease and each claim runs
+/// on tokio's blocking pool, as `AsyncGitLedger` explains.
+#[derive(Clone, Debug)]
+pub struct AsyncBlobGitLedger {
+    inner: BlobGitLedger,
+}
+
+impl AsyncBlobGitLedger {
+    pub fn new(inner: BlobGitLedger) -> AsyncBlobGitLedger {
+        AsyncBlobGitLedger { inner }
+    }
+
+    pub async fn lock(&mut self) -> LedgerResult<AsyncBlobGitLedgerGuard> {
+        let started = Instant::now();
+        let policy = self.inner.retry_policy().clone();
+        let mut retry = policy.start();
+        loop {
+            let mut wait = self.inner.lease_wait();
+            let (commit, data) = loop {
+                log::trace!("Fetch remote data");
+                let ledger = self.inner.clone();
+                let (commit, data, lease) = blocking(move || {
+                    ledger.inner().fetch_refs()?;
+                    ledger.read_state(ledger.inner().read_branch()?)
+                })
+                .await??;
+                match wait.observe(lease) {
+                    None => break (commit, data),
+                    Some(duration) => tokio::time::sleep(retry.wait(duration)?).await,
+                }
+            };
+
+            let ledger = self.inner.clone();
+            let expired = wait.expired();
+            let claim = move || ledger.claim(commit, data, started, expired);
+            if let Some(guard) = blocking(claim).await?? {
+                return Ok(AsyncBlobGitLedgerGuard::new(guard));
+            }
+            tokio::time::sleep(retry.failed()?).await;
+        }
+    }
+}
+
+/// Async counterpart of `BlobGitLedgerGuard`. Dropping the guard, or a future
+/// that is renewing or updating it, releases the lease on the blocking pool.
+pub struct AsyncBlobGitLedgerGuard {
+    inner: Option<BlobGitLedgerGuard>,
+    lease: u64,
+}
+
+impl AsyncBlobGitLedgerGuard {
+    fn new(inner: BlobGitLedgerGuard) -> AsyncBlobGitLedgerGuard {
+        AsyncBlobGitLedgerGuard {
+            lease: inner.lease(),
+            inner: Some(inner),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.inner.as_ref().map(|guard| guard.data()).unwrap_or(&[])
+    }
+
+    /// Update the data and renew the lease.
+    pub async fn update(&mut self, data: &[u8]) -> LedgerResult<()> {
+        let data = data.to_vec();
+        self.with_guard(move |guard| guard.update(&data)).await
+    }
+
+    /// Update the data and release the lease.
+    pub async fn update_and_release(mut self, data: &[u8]) -> LedgerResult<()> {
+        let data = data.to_vec();
+        let guard = self.take()?;
+        blocking(move || guard.update_and_release(&data)).await?
+    }
+
+    /// Release the lease. This will give an error if it was lost.
+    pub async fn release(mut self) -> LedgerResult<()> {
+        let guard = self.take()?;
+        blocking(move || guard.release()).await?
+    }
+
+    /// Renew the lease.
+    pub async fn renew(&mut self) -> LedgerResult<()> {
+        self.with_guard(|guard| guard.renew()).await
+    }
+
+    /// Run `f` on the blocking pool. The guard travels with it, so that if
+    /// this future is dropped the guard is dropped (releasing the lease) once
+    /// `f` finishes.
+    async fn with_guard<F>(&mut self, f: F) -> LedgerResult<()>
+    where
+        F: FnOnce(&mut BlobGitLedgerGuard) -> LedgerResult<()> + Send + 'static,
+    {
+        let mut guard = self.take()?;
+        let (guard, result) = blocking(move || {
+            let result = f(&mut guard);
+            (guard, result)
+        })
+        .await?;
+        self.lease = guard.lease();
+        self.inner = Some(guard);
+        result
+    }
+
+    /// An earlier operation on this guard was cancelled, which released the
+    /// lease.
+    fn take(&mut self) -> LedgerResult<BlobGitLedgerGuard> {
+        self.inner
+            .take()
+            .ok_or(LedgerError::LostLease { lease: self.lease })
+    }
+}
+
+impl Drop for AsyncBlobGitLedgerGuard {
+    fn drop(&mut self) {
+        let guard = match self.inner.take() {
+            Some(guard) => guard,
+            None => return,
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(move || drop(guard));
+            }
+            Err(..) => drop(guard),
+        }
+    }
+}
+
+fn fetch_refs(ledger: &GitLedger) -> impl Future<Output = LedgerResult<()>> + Send + 'static {
+    let ledger = ledger.clone();
+    async move { blocking(move || ledger.fetch_refs()).await? }
+}
+
+async fn blocking<F, T>(f: F) -> LedgerResult<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => Ok(result),
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(LedgerError::Repository(e.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use gix::object::Kind;
+    use gix_object::tree::{Entry, EntryMode};
+
+    use crate::testing;
+
+    fn assert_send<T: Send>(t: T) -> T {
+        t
+    }
+
+    #[tokio::test]
+    async fn test_update_with() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let mut ledger1 = AsyncGitLedger::new(testing::ledger(tmp.path(), 1));
+        let mut ledger2 = AsyncGitLedger::new(testing::ledger(tmp.path(), 2));
+
+        for j in [1, 2, 1] {
+            let ledger = if j == 1 { &mut ledger1 } else { &mut ledger2 };
+            assert_send(ledger.update_with(|repo, st| {
+                let n: u64 = match st {
+                    None => 0,
+                    Some((_commit, tree)) => {
+                        let a = tree.lookup_entry_by_path("single").unwrap().unwrap();
+                        let a = repo.find_object(a.oid()).unwrap();
+                        assert_eq!(a.kind, Kind::Blob);
+                        std::str::from_utf8(&a.data).unwrap().parse().unwrap()
+                    }
+                };
+                let a = repo.write_blob((n + 1).to_string())?;
+                let mut tb = TreeBuilder::empty();
+                tb.entries.push(Entry {
+                    oid: a.into(),
+                    mode: EntryMode::Blob,
+                    filename: "single".into(),
+                });
+                anyhow::Ok(tb)
+            }))
+            .await
+            .unwrap();
+        }
+
+        let (_commit, tree) = ledger2.fetch().await.unwrap().unwrap();
+        let a = tree.lookup_entry_by_path("single").unwrap().unwrap();
+        assert_eq!(&a.object().unwrap().data[..], b"3");
+    }
+
+    #[tokio::test]
+    async fn test_blob_ledger() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let mut ledger = AsyncBlobGitLedger::new(BlobGitLedger::new(
+            testing::ledger(tmp.path(), 1),
+            Duration::from_millis(50),
+            Duration::from_millis(500),
+        ));
+
+        let mut guard = assert_send(ledger.lock()).await.unwrap();
+        assert_eq!(guard.data(), b"");
+        guard.update(b"foo").await.unwrap();
+        guard.renew().await.unwrap();
+        assert_eq!(guard.data(), b"foo");
+        guard.update_and_release(b"bar").await.unwrap();
+
+        let guard = ledger.lock().await.unwrap();
+        assert_eq!(guard.data(), b"bar");
+        guard.release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_lock() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let mut ledger = AsyncBlobGitLedger::new(BlobGitLedger::new(
+            testing::ledger(tmp.path(), 1),
+            Duration::from_millis(50),
+            Duration::from_secs(60),
+        ));
+        let upstream_lease = |ledger: &AsyncBlobGitLedger| {
+            let ledger = ledger.inner.clone();
+            blocking(move || {
+                ledger.inner().fetch_refs()?;
+                ledger.read_state(ledger.inner().read_branch()?)
+            })
+        };
+
+        // However far a lock got before being cancelled, it leaves no lease
+        // claimed: leases last a minute, so the next lock would time out.
+        for delay in [0, 1, 2, 5, 10, 20, 50] {
+            let locked = tokio::time::timeout(Duration::from_millis(delay), ledger.lock()).await;
+            if let Ok(guard) = locked {
+                guard.unwrap().release().await.unwrap();
+            }
+            let guard = tokio::time::timeout(Duration::from_secs(5), ledger.lock())
+                .await
+                .expect("a cancelled lock left its lease claimed")
+                .unwrap();
+            guard.release().await.unwrap();
+        }
+
+        // Cancelling a lock waiting on someone else's lease leaves theirs.
+        let guard = ledger.lock().await.unwrap();
+        let held = guard.lease;
+        std::mem::forget(guard);
+        let locked = tokio::time::timeout(Duration::from_millis(200), ledger.lock()).await;
+        assert!(locked.is_err());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (_commit, _data, lease) = upstream_lease(&ledger).await.unwrap().unwrap();
+        assert_eq!(lease, held);
+    }
+}
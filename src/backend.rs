@@ -0,0 +1,405 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use gix::object::Kind;
+use gix::prelude::{Find, Write};
+use gix::Repository;
+use gix_hash::ObjectId;
+
+use crate::error::{LedgerError, LedgerResult};
+use crate::push::{missing_objects, RefPush};
+
+/// Where a ledger created with `GitLedger::on_backend` keeps its branch in
+/// place of a git remote. The ledger's local repository still holds the
+/// objects it reads and writes; a backend only has to move them and the
+/// branch tip in and out of it.
+pub trait Backend: Send + Sync + fmt::Debug {
+    /// Copy the tip of `branch_ref`, and everything reachable from it that
+    /// `repo` lacks, into `repo`, returning the tip, or `None` if the branch
+    /// does not exist.
+    fn fetch(&self, repo: &Repository, branch_ref: &str) -> LedgerResult<Option<ObjectId>>;
+
+    /// Copy `new` and everything reachable from it out of `repo`, then move
+    /// `branch_ref` to `new` if it still points at `expected`, where `None`
+    /// means the branch must not exist.
+    fn push(
+        &self,
+        repo: &Repository,
+        branch_ref: &str,
+        new: ObjectId,
+        expected: Option<ObjectId>,
+    ) -> LedgerResult<RefPush>;
+}
+
+/// `Backend` holding objects and branches in memory, for testing code built
+/// on ledgers without a remote repository. Share one between ledgers with an
+/// `Arc` to have them race each other. Each ledger still needs a local
+/// repository on disk, as `GitLedger::on_backend` explains.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    store: Mutex<Store>,
+}
+
+#[derive(Default)]
+struct Store {
+    objects: HashMap<ObjectId, (Kind, Vec<u8>)>,
+    refs: BTreeMap<String, ObjectId>,
+}
+
+impl fmt::Debug for Store {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Store")
+            .field("objects", &self.objects.len())
+            .field("refs", &self.refs)
+            .finish()
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    /// The tip of `branch_ref`, if it exists.
+    pub fn branch(&self, branch_ref: &str) -> Option<ObjectId> {
+        self.store.lock().unwrap().refs.get(branch_ref).copied()
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn fetch(&self, repo: &Repository, branch_ref: &str) -> LedgerResult<Option<ObjectId>> {
+        let store = self.store.lock().unwrap();
+        let Some(tip) = store.refs.get(branch_ref).copied() else {
+            return Ok(None);
+        };
+        let find = |id| {
+            store.objects.get(&id).cloned().ok_or_else(|| {
+                LedgerError::Repository(anyhow::anyhow!("object {} missing from backend", id))
+            })
+        };
+        for id in missing_objects(tip, |id| repo.objects.contains(id), find)? {
+            let (kind, data) = &store.objects[&id];
+            repo.objects
+                .write_buf(*kind, data)
+                .map_err(|e| anyhow::anyhow!(e))
+                .context("write fetched object")?;
+        }
+        Ok(Some(tip))
+    }
+
+    fn push(
+        &self,
+        repo: &Repository,
+        branch_ref: &str,
+        new: ObjectId,
+        expected: Option<ObjectId>,
+    ) -> LedgerResult<RefPush> {
+        let mut store = self.store.lock().unwrap();
+        if store.refs.get(branch_ref).copied() != expected {
+            return Ok(RefPush::Stale);
+        }
+        let find = |id| {
+            let object = repo.find_object(id).context("find object to push")?;
+            Ok((object.kind, object.detach().data))
+        };
+        let missing = missing_objects(new, |id| store.objects.contains_key(&id), find)?;
+        for id in missing {
+            let object = repo.find_object(id).context("find object to push")?;
+            store
+                .objects
+                .insert(id, (object.kind, object.detach().data));
+        }
+        store.refs.insert(branch_ref.to_string(), new);
+        Ok(RefPush::Updated)
+    }
+}
+
+/// A fault for `FaultyBackend` to inject into one fetch or push.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Fail with `RemoteUnreachable` without passing the operation on.
+    Drop,
+    /// Wait this long, then pass the operation on.
+    Delay(Duration),
+    /// Pass the operation on, then fail with `RemoteUnreachable` whatever
+    /// happened, as when a push lands but the connection drops before the
+    /// reply.
+    Lost,
+    /// Take the operation out of order. A fetch answers with what the
+    /// previous fetch of the branch saw. A push fails with
+    /// `RemoteUnreachable` and is passed on after the next operation.
+    Reorder,
+}
+
+/// `Backend` wrapping another to inject faults into its fetches and pushes.
+/// Faults are queued separately for fetches and pushes, and each operation
+/// takes the next one from its queue; operations are passed on unchanged
+/// while the queue is empty.
+#[derive(Debug)]
+pub struct FaultyBackend<B> {
+    inner: B,
+    state: Mutex<Faults>,
+}
+
+#[derive(Default)]
+struct Faults {
+    fetches: VecDeque<Fault>,
+    pushes: VecDeque<Fault>,
+    last_fetched: HashMap<String, Option<ObjectId>>,
+    deferred: Vec<DeferredPush>,
+}
+
+struct DeferredPush {
+    repo: Repository,
+    branch_ref: String,
+    new: ObjectId,
+    expected: Option<ObjectId>,
+}
+
+impl fmt::Debug for Faults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Faults")
+            .field("fetches", &self.fetches)
+            .field("pushes", &self.pushes)
+            .field("deferred", &self.deferred.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: Backend> FaultyBackend<B> {
+    pub fn new(inner: B) -> FaultyBackend<B> {
+        FaultyBackend {
+            inner,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Inject `fault` into the first fetch not yet given one.
+    pub fn inject_fetch(&self, fault: Fault) {
+        self.state.lock().unwrap().fetches.push_back(fault);
+    }
+
+    /// Inject `fault` into the first push not yet given one.
+    pub fn inject_push(&self, fault: Fault) {
+        self.state.lock().unwrap().pushes.push_back(fault);
+    }
+
+    /// Pass on the pushes held back by `Fault::Reorder`.
+    fn deliver(&self, deferred: Vec<DeferredPush>) {
+        for push in deferred {
+            let result = self
+                .inner
+                .push(&push.repo, &push.branch_ref, push.new, push.expected);
+            log::trace!(
+                "FaultyBackend: delivered push of {}: {:?}",
+                push.new,
+                result
+            );
+        }
+    }
+}
+
+fn unreachable(operation: &str, fault: Fault) -> LedgerError {
+    LedgerError::RemoteUnreachable(anyhow::anyhow!("{} failed by {:?}", operation, fault))
+}
+
+impl<B: Backend> Backend for FaultyBackend<B> {
+    fn fetch(&self, repo: &Repository, branch_ref: &str) -> LedgerResult<Option<ObjectId>> {
+        let (fault, deferred, last) = {
+            let mut state = self.state.lock().unwrap();
+            (
+                state.fetches.pop_front(),
+                std::mem::take(&mut state.deferred),
+                state.last_fetched.get(branch_ref).copied().flatten(),
+            )
+        };
+        let result = match fault {
+            None => self.inner.fetch(repo, branch_ref),
+            Some(Fault::Drop) => Err(unreachable("fetch", Fault::Drop)),
+            Some(Fault::Delay(delay)) => {
+                std::thread::sleep(delay);
+                self.inner.fetch(repo, branch_ref)
+            }
+            Some(Fault::Lost) => self
+                .inner
+                .fetch(repo, branch_ref)
+                .and(Err(unreachable("fetch", Fault::Lost))),
+            Some(Fault::Reorder) => Ok(last),
+        };
+        self.deliver(deferred);
+        if let (Ok(tip), None | Some(Fault::Delay(..))) = (&result, fault) {
+            self.state
+                .lock()
+                .unwrap()
+                .last_fetched
+                .insert(branch_ref.to_string(), *tip);
+        }
+        result
+    }
+
+    fn push(
+        &self,
+        repo: &Repository,
+        branch_ref: &str,
+        new: ObjectId,
+        expected: Option<ObjectId>,
+    ) -> LedgerResult<RefPush> {
+        let (fault, deferred) = {
+            let mut state = self.state.lock().unwrap();
+            (
+                state.pushes.pop_front(),
+                std::mem::take(&mut state.deferred),
+            )
+        };
+        let result = match fault {
+            None => self.inner.push(repo, branch_ref, new, expected),
+            Some(Fault::Drop) => Err(unreachable("push", Fault::Drop)),
+            Some(Fault::Delay(delay)) => {
+                std::thread::sleep(delay);
+                self.inner.push(repo, branch_ref, new, expected)
+            }
+            Some(Fault::Lost) => self
+                .inner
+                .push(repo, branch_ref, new, expected)
+                .and(Err(unreachable("push", Fault::Lost))),
+            Some(Fault::Reorder) => {
+                self.state.lock().unwrap().deferred.push(DeferredPush {
+                    repo: repo.clone(),
+                    branch_ref: branch_ref.to_string(),
+                    new,
+                    expected,
+                });
+                Err(unreachable("push", Fault::Reorder))
+            }
+        };
+        self.deliver(deferred);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::testing::{self, tip, tree};
+    use crate::{GitLedger, LedgerSet, PushOutcome, RetryPolicy, Transaction};
+
+    #[test]
+    fn test_memory_backend() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let backend = Arc::new(MemoryBackend::new());
+        let make_ledger = |name: &str| {
+            GitLedger::on_backend(tmp.path().join(name), backend.clone(), "main".to_string())
+                .unwrap()
+        };
+        let (ledger1, ledger2) = (make_ledger("local1"), make_ledger("local2"));
+
+        let first = ledger1
+            .push(None, &tree(&ledger1.repo, "0"))
+            .unwrap()
+            .committed()
+            .unwrap();
+        assert_eq!(backend.branch("refs/heads/main"), Some(first));
+        assert_eq!(
+            ledger2.push(None, &tree(&ledger2.repo, "1")).unwrap(),
+            PushOutcome::RaceLost {
+                expected: None,
+                remote: Some(first),
+            }
+        );
+
+        let second = ledger2
+            .update_with(|_repo, old| {
+                let (commit, _tree) = old.unwrap();
+                assert_eq!(commit.id, first);
+                anyhow::Ok(tree(&ledger2.repo, "1"))
+            })
+            .unwrap();
+        let (commit, tree) = ledger1.fetch().unwrap().unwrap();
+        assert_eq!(commit.id, second);
+        let entry = tree.lookup_entry_by_path("single").unwrap().unwrap();
+        assert_eq!(&*ledger1.repo.find_object(entry.oid()).unwrap().data, b"1");
+    }
+
+    #[test]
+    fn test_faulty_backend() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let backend = Arc::new(FaultyBackend::new(MemoryBackend::new()));
+        let make_ledger = |name: &str| {
+            GitLedger::on_backend(tmp.path().join(name), backend.clone(), "main".to_string())
+                .unwrap()
+                .with_retry_policy(RetryPolicy::immediate())
+        };
+        let (ledger1, ledger2) = (make_ledger("local1"), make_ledger("local2"));
+
+        // A push reported as failed may still have landed.
+        backend.inject_push(Fault::Lost);
+        assert!(matches!(
+            ledger1.push(None, &tree(&ledger1.repo, "0")),
+            Err(LedgerError::RemoteUnreachable(..))
+        ));
+        let first = tip(&ledger1).unwrap();
+
+        // A dropped fetch fails, and the next goes through.
+        backend.inject_fetch(Fault::Drop);
+        assert!(matches!(
+            ledger2.fetch(),
+            Err(LedgerError::RemoteUnreachable(..))
+        ));
+        assert_eq!(tip(&ledger2), Some(first));
+
+        // A push held back lands after the next operation.
+        backend.inject_push(Fault::Reorder);
+        assert!(ledger1
+            .push(Some(first), &tree(&ledger1.repo, "1"))
+            .is_err());
+        assert_eq!(backend.inner().branch("refs/heads/main"), Some(first));
+        backend.inject_fetch(Fault::Delay(Duration::from_millis(10)));
+        assert_eq!(tip(&ledger2), Some(first));
+        let second = backend.inner().branch("refs/heads/main").unwrap();
+        assert_ne!(second, first);
+
+        // A stale fetch leads to a push that loses the race, and the retry
+        // starts from the push that beat it.
+        backend.inject_fetch(Fault::Reorder);
+        let mut seen = Vec::new();
+        ledger2
+            .update_with(|_repo, old| {
+                seen.push(old.unwrap().0.id);
+                anyhow::Ok(tree(&ledger2.repo, "2"))
+            })
+            .unwrap();
+        assert_eq!(seen, [first, second]);
+    }
+
+    #[test]
+    fn test_backend_unsupported() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let backend = Arc::new(MemoryBackend::new());
+        let ledger =
+            GitLedger::on_backend(tmp.path().join("local"), backend, "main".to_string()).unwrap();
+        let unsupported = |e: LedgerError| {
+            assert!(format!("{:?}", e).contains("not supported for ledgers on a backend"));
+        };
+
+        unsupported(Transaction::new(vec![ledger.clone()]).unwrap_err());
+
+        let set = LedgerSet::new(
+            tmp.path().join("set"),
+            testing::upstream(tmp.path(), "upstream"),
+            "origin".to_string(),
+            "tenants/".to_string(),
+        )
+        .unwrap();
+        unsupported(set.fetch(&[&ledger]).unwrap_err());
+    }
+}
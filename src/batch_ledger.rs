@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+use anyhow::Context;
+use gix::Repository;
+use gix_hash::ObjectId;
+use gix_object::Tree as TreeBuilder;
+
+use crate::{GitLedger, LedgerError, LedgerResult, PushOutcome};
+
+type Update =
+    Box<dyn FnMut(&Repository, Option<gix::Tree<'_>>) -> anyhow::Result<TreeBuilder> + Send>;
+
+/// `GitLedger` that commits concurrent updates from clones of itself in
+/// groups. While one caller fetches and pushes, updates from other callers
+/// queue up; the next caller to find the ledger idle applies every queued
+/// update in turn to one fetched tree and pushes them as a single commit.
+///
+/// Each update sees the tree left by the updates before it in its group,
+/// which is `None` only if the ledger is empty. An update that fails is left
+/// out of its group, and if the group loses a race, the remaining updates are
+/// all applied again to the new upstream state.
+#[derive(Clone, Debug)]
+pub struct BatchLedger {
+    inner: GitLedger,
+    queue: Arc<Queue>,
+}
+
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+    idle: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_id: u64,
+    pending: Vec<(u64, Update)>,
+    committing: bool,
+    done: HashMap<u64, LedgerResult<ObjectId>>,
+}
+
+impl std::fmt::Debug for Queue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Queue").finish_non_exhaustive()
+    }
+}
+
+impl BatchLedger {
+    pub fn new(inner: GitLedger) -> BatchLedger {
+        BatchLedger {
+            inner,
+            queue: Arc::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &GitLedger {
+        &self.inner
+    }
+
+    /// Apply `f` to the ledger as part of the next group commit, returning
+    /// the commit that included it. `f` may run on the thread of another
+    /// caller, and runs again each time its group loses a race.
+    pub fn update<F, E>(&self, mut f: F) -> LedgerResult<ObjectId>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnMut(&Repository, Option<gix::Tree<'_>>) -> std::result::Result<TreeBuilder, E>
+            + Send
+            + 'static,
+    {
+        let mut state = self.queue.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.pending.push((
+            id,
+            Box::new(move |repo, tree| f(repo, tree).map_err(Into::into)),
+        ));
+
+        loop {
+            if let Some(result) = state.done.remove(&id) {
+                return result;
+            }
+            if state.committing {
+                state = self.queue.idle.wait(state).unwrap();
+                continue;
+            }
+
+            state.committing = true;
+            let group = std::mem::take(&mut state.pending);
+            drop(state);
+            log::trace!("BatchLedger: committing {} updates", group.len());
+            let mut committing = Committing {
+                queue: &self.queue,
+                ids: group.iter().map(|(id, _)| *id).collect(),
+                results: Vec::new(),
+            };
+            committing.results = self.commit(group);
+            drop(committing);
+            state = self.queue.state.lock().unwrap();
+        }
+    }
+
+    /// Push `group` as one commit, returning the result for each update.
+    fn commit(&self, mut group: Vec<(u64, Update)>) -> Vec<(u64, LedgerResult<ObjectId>)> {
+        let mut results = Vec::new();
+        let mut retry = self.inner.retry_policy().start();
+        loop {
+            let (old_commit, tree) = match self.apply(&mut group, &mut results) {
+                Ok(applied) => applied,
+                Err(e) => return fail(group, results, e),
+            };
+            let tree = match tree {
+                Some(tree) => tree,
+                None => return results,
+            };
+            match self.inner.push(old_commit, &tree) {
+                Ok(PushOutcome::Committed(commit)) => {
+                    results.extend(group.into_iter().map(|(id, _)| (id, Ok(commit))));
+                    return results;
+                }
+                Ok(PushOutcome::RaceLost { .. }) => {}
+                Err(e) => return fail(group, results, e),
+            }
+            match retry.failed() {
+                Ok(wait) => std::thread::sleep(wait),
+                Err(e) => return fail(group, results, e),
+            }
+        }
+    }
+
+    /// Fetch and apply each update in `group` in turn, moving the failed ones
+    /// to `results`. Returns the fetched commit and the final tree, or no
+    /// tree if every update failed. On error `group` is left whole, so the
+    /// caller can report it to every update.
+    fn apply(
+        &self,
+        group: &mut Vec<(u64, Update)>,
+        results: &mut Vec<(u64, LedgerResult<ObjectId>)>,
+    ) -> LedgerResult<(Option<ObjectId>, Option<TreeBuilder>)> {
+        let repo = &self.inner.repo;
+        let (old_commit, mut tree) = match self.inner.fetch()? {
+            Some((commit, tree)) => (Some(commit.id), Some(tree.id)),
+            None => (None, None),
+        };
+
+        let mut last = None;
+        let mut failed = Vec::new();
+        for (id, update) in group.iter_mut() {
+            let current = match tree {
+                Some(tree) => Some(
+                    repo.find_object(tree)
+                        .context("find tree")?
+                        .try_into_tree()
+                        .context("expected tree")?,
+                ),
+                None => None,
+            };
+            match update(repo, current) {
+                Ok(tb) => {
+                    tree = Some(
+                        repo.write_object(&tb)
+                            .context("write tree to git")?
+                            .detach(),
+                    );
+                    last = Some(tb);
+                }
+                Err(e) => failed.push((*id, e)),
+            }
+        }
+        group.retain(|(id, _)| !failed.iter().any(|(failed, _)| failed == id));
+        results.extend(
+            failed
+                .into_iter()
+                .map(|(id, e)| (id, Err(LedgerError::Update(e)))),
+        );
+        Ok((old_commit, last))
+    }
+}
+
+/// Hands the results of a group back to its callers, and the queue on to
+/// the next caller, even if an update panics. Every update in the group gets
+/// a result, so none of its callers waits for one forever.
+struct Committing<'a> {
+    queue: &'a Queue,
+    ids: Vec<u64>,
+    results: Vec<(u64, LedgerResult<ObjectId>)>,
+}
+
+impl Drop for Committing<'_> {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.done.extend(self.results.drain(..));
+        let panicking = std::thread::panicking();
+        for id in &self.ids {
+            state.done.entry(*id).or_insert_with(|| {
+                Err(LedgerError::Update(if panicking {
+                    anyhow::anyhow!("an update in the same group panicked")
+                } else {
+                    anyhow::anyhow!("the group finished without a result for this update")
+                }))
+            });
+        }
+        state.committing = false;
+        self.queue.idle.notify_all();
+    }
+}
+
+/// Report `e` to every update still in `group`.
+fn fail(
+    group: Vec<(u64, Update)>,
+    mut results: Vec<(u64, LedgerResult<ObjectId>)>,
+    e: LedgerError,
+) -> Vec<(u64, LedgerResult<ObjectId>)> {
+    results.extend(group.into_iter().map(|(id, _)| (id, Err(e.duplicate()))));
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use gix_object::tree::{Entry, EntryMode};
+
+    use crate::testing;
+    use crate::Metrics;
+
+    fn add(
+        repo: &Repository,
+        tree: Option<gix::Tree<'_>>,
+        name: &str,
+    ) -> anyhow::Result<TreeBuilder> {
+        let mut tb = match tree {
+            Some(tree) => TreeBuilder::from(tree.decode()?),
+            None => TreeBuilder::empty(),
+        };
+        tb.entries.push(Entry {
+            oid: repo.write_blob(name)?.into(),
+            mode: EntryMode::Blob,
+            filename: name.into(),
+        });
+        tb.entries.sort();
+        Ok(tb)
+    }
+
+    #[test]
+    fn test_batch_ledger() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let ledger = BatchLedger::new(
+            testing::ledger_on(tmp.path(), "local", "main").with_observer(metrics.clone()),
+        );
+
+        // Hold up the first commit until every other update is queued.
+        let queued = Arc::new(AtomicUsize::new(0));
+        let first = {
+            let (ledger, queued) = (ledger.clone(), queued.clone());
+            std::thread::spawn(move || {
+                ledger.update(move |repo, tree| {
+                    while queued.load(Ordering::SeqCst) < 8 {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                    add(repo, tree, "first")
+                })
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let (ledger, queued) = (ledger.clone(), queued.clone());
+                std::thread::spawn(move || {
+                    queued.fetch_add(1, Ordering::SeqCst);
+                    if i == 0 {
+                        ledger.update(|_, _| Err(anyhow::anyhow!("refused")))
+                    } else {
+                        ledger.update(move |repo, tree| add(repo, tree, &format!("entry{}", i)))
+                    }
+                })
+            })
+            .collect();
+
+        let first = first.join().unwrap().unwrap();
+        let mut commits: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(matches!(commits.remove(0), Err(LedgerError::Update(..))));
+        let second = commits[0].as_ref().unwrap();
+        assert_ne!(*second, first);
+        assert!(commits
+            .iter()
+            .all(|commit| commit.as_ref().unwrap() == second));
+        assert_eq!(metrics.snapshot().pushes, 2);
+
+        let (_commit, tree) = ledger.inner().fetch().unwrap().unwrap();
+        assert_eq!(tree.decode().unwrap().entries.len(), 8);
+    }
+}
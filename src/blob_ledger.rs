@@ -1,6 +1,6 @@
-use anyhow::{Context, Result};
+use anyhow::Context;
 use gix::object::Kind;
-use gix::{Repository, Tree};
+use gix::{Commit, Repository, Tree};
 use gix_hash::ObjectId;
 use gix_object::{
     tree::{self, EntryMode},
@@ -8,17 +8,19 @@ use gix_object::{
 };
 use rand::Rng;
 use std::convert::TryInto;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use crate::GitLedger;
+use crate::{GitLedger, LedgerError, LedgerResult, Observer, PushOutcome, RetryPolicy};
 
 /// Degenerate case of `GitLedger` where state is a single blob, permitting a
 /// simpler API. Locks with a lease.
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct BlobGitLedger {
     inner: GitLedger,
     poll_time: Duration,
     lease_length: Duration,
+    retry_policy: RetryPolicy,
 }
 
 pub struct BlobGitLedgerGuard {
@@ -36,88 +38,187 @@ impl BlobGitLedger {
             lease_length
         );
         BlobGitLedger {
+            retry_policy: inner.retry_policy().clone(),
             inner,
             poll_time,
             lease_length,
         }
     }
 
-    pub fn lock(&self) -> Result<BlobGitLedgerGuard> {
+    /// Bound `lock` by `policy`. Its deadline covers time spent waiting for
+    /// another lease to expire; its attempts count races lost while claiming.
+    /// Defaults to the policy of the inner `GitLedger`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> BlobGitLedger {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Report lease activity to `observer`, along with the fetches, pushes
+    /// and lost races of the inner `GitLedger`.
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> BlobGitLedger {
+        self.inner = self.inner.with_observer(observer);
+        self
+    }
+
+    pub fn lock(&self) -> LedgerResult<BlobGitLedgerGuard> {
+        let started = Instant::now();
+        let mut retry = self.retry_policy.start();
         loop {
-            let mut start_time = Instant::now();
-            let mut old_lease = 0;
-            log::trace!(
-                "Attempt to lock BlobGitLedger start_time={:?} old_lease={:?}",
-                start_time,
-                old_lease
-            );
+            let mut wait = self.lease_wait();
             let (commit, data) = loop {
                 log::trace!("Fetch remote data");
-                let (commit, data, lease) = match self.inner.fetch()? {
-                    None => {
-                        log::trace!("No remote data found; using default.");
-                        (None, Vec::default(), 0)
-                    }
-                    Some((commit, tree)) => {
-                        let (data, lease) = decode(&self.inner.repo, tree)?;
-                        let commit_id: ObjectId = commit.id.into();
-                        log::trace!("Found commit {}", &commit_id);
-                        (Some(commit_id), data, lease)
-                    }
-                };
-
-                if lease == 0 {
-                    log::trace!("Existing lease=0; claiming immediately");
-                    break (commit, data);
+                let (commit, data, lease) = self.read_state(self.inner.fetch()?)?;
+                match wait.observe(lease) {
+                    None => break (commit, data),
+                    Some(duration) => std::thread::sleep(retry.wait(duration)?),
                 }
+            };
 
-                if lease != old_lease {
-                    start_time = Instant::now();
-                    log::trace!(
-                        "old_lease={}; remote lease={}, waiting for expiry starting at {:?}",
-                        old_lease,
-                        lease,
-                        start_time
-                    );
-                    old_lease = lease;
-                }
+            if let Some(guard) = self.claim(commit, data, started, wait.expired())? {
+                return Ok(guard);
+            }
+            std::thread::sleep(retry.failed()?);
+        }
+    }
 
-                let elapsed = start_time.elapsed();
+    /// Decode the fetched ledger state into its commit, data and lease.
+    pub(crate) fn read_state(
+        &self,
+        state: Option<(Commit<'_>, Tree<'_>)>,
+    ) -> LedgerResult<(Option<ObjectId>, Vec<u8>, u64)> {
+        match state {
+            None => {
+                log::trace!("No remote data found; using default.");
+                Ok((None, Vec::default(), 0))
+            }
+            Some((commit, tree)) => {
+                let (data, lease) = decode(&self.inner.repo, tree)?;
+                let commit_id: ObjectId = commit.id;
+                log::trace!("Found commit {}", &commit_id);
+                Ok((Some(commit_id), data, lease))
+            }
+        }
+    }
 
-                if elapsed >= self.lease_length {
-                    log::trace!(
-                        "Waited long enough for remote lease {} to expire",
-                        old_lease
-                    );
-                    break (commit, data);
+    /// Try to take a fresh lease over `commit`, returning `None` if someone
+    /// else wrote first. `lock` began at `started`, and `expired` is the
+    /// unreleased lease being taken over, if any.
+    pub(crate) fn claim(
+        &self,
+        commit: Option<ObjectId>,
+        data: Vec<u8>,
+        started: Instant,
+        expired: Option<u64>,
+    ) -> LedgerResult<Option<BlobGitLedgerGuard>> {
+        let lease: u64 = rand::thread_rng().gen();
+        log::trace!("Acquiring with lease={}", lease);
+        let tb = encode(&self.inner.repo, &data, lease)?;
+        Ok(match self.inner.push(commit, &tb)? {
+            PushOutcome::Committed(commit) => {
+                let observers = &self.inner.observers;
+                if let Some(expired) = expired {
+                    observers.lease_stolen(expired);
                 }
-
-                log::trace!(
-                    "Sleeping; waiting for lease {}; remaining={:?}",
-                    old_lease,
-                    (self.lease_length - elapsed)
-                );
-                std::thread::sleep(std::cmp::min(
-                    self.poll_time,
-                    self.lease_length
-                        .checked_sub(elapsed)
-                        .unwrap_or(self.lease_length),
-                ));
-            };
-
-            let lease: u64 = rand::thread_rng().gen();
-            log::trace!("Acquiring with lease={}", lease);
-            let tb = encode(&self.inner.repo, &data, lease)?;
-            if let Some(commit) = self.inner.push(commit, &tb)? {
-                return Ok(BlobGitLedgerGuard {
+                observers.lease_acquired(lease, started.elapsed());
+                Some(BlobGitLedgerGuard {
                     inner: self.inner.clone(),
                     lease,
                     commit: Some(commit),
                     data,
-                });
+                })
             }
+            PushOutcome::RaceLost { .. } => None,
+        })
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn inner(&self) -> &GitLedger {
+        &self.inner
+    }
+
+    pub(crate) fn lease_wait(&self) -> LeaseWait {
+        LeaseWait::new(self.poll_time, self.lease_length)
+    }
+}
+
+/// Tracks how long the remote lease has gone unchanged while waiting for it
+/// to expire.
+pub(crate) struct LeaseWait {
+    start_time: Instant,
+    old_lease: u64,
+    expired: Option<u64>,
+    poll_time: Duration,
+    lease_length: Duration,
+}
+
+impl LeaseWait {
+    fn new(poll_time: Duration, lease_length: Duration) -> LeaseWait {
+        let start_time = Instant::now();
+        let old_lease = 0;
+        log::trace!(
+            "Attempt to lock BlobGitLedger start_time={:?} old_lease={:?}",
+            start_time,
+            old_lease
+        );
+        LeaseWait {
+            start_time,
+            old_lease,
+            expired: None,
+            poll_time,
+            lease_length,
         }
     }
+
+    /// The unreleased lease `observe` last found expired, if any.
+    pub(crate) fn expired(&self) -> Option<u64> {
+        self.expired
+    }
+
+    /// Given the lease currently held upstream, return `None` if it may be
+    /// claimed now, or how long to sleep before fetching again.
+    pub(crate) fn observe(&mut self, lease: u64) -> Option<Duration> {
+        if lease == 0 {
+            log::trace!("Existing lease=0; claiming immediately");
+            self.expired = None;
+            return None;
+        }
+
+        if lease != self.old_lease {
+            self.start_time = Instant::now();
+            log::trace!(
+                "old_lease={}; remote lease={}, waiting for expiry starting at {:?}",
+                self.old_lease,
+                lease,
+                self.start_time
+            );
+            self.old_lease = lease;
+        }
+
+        let elapsed = self.start_time.elapsed();
+
+        if elapsed >= self.lease_length {
+            log::trace!(
+                "Waited long enough for remote lease {} to expire",
+                self.old_lease
+            );
+            self.expired = Some(self.old_lease);
+            return None;
+        }
+
+        log::trace!(
+            "Sleeping; waiting for lease {}; remaining={:?}",
+            self.old_lease,
+            (self.lease_length - elapsed)
+        );
+        Some(std::cmp::min(
+            self.poll_time,
+            self.lease_length.saturating_sub(elapsed),
+        ))
+    }
 }
 
 impl BlobGitLedgerGuard {
@@ -125,64 +226,72 @@ impl BlobGitLedgerGuard {
         &self.data
     }
 
+    #[cfg(feature = "async")]
+    pub(crate) fn lease(&self) -> u64 {
+        self.lease
+    }
+
     /// Update the data and renew the lease.
-    pub fn update(&mut self, data: &[u8]) -> Result<()> {
+    pub fn update(&mut self, data: &[u8]) -> LedgerResult<()> {
         let old_lease = self.lease;
         self.lease = rand::thread_rng().gen();
-        let tb = encode(&self.inner.repo, &data, self.lease)?;
-        let commit = self
-            .inner
-            .push(self.commit, &tb)?
-            .with_context(|| format!("Lost lease {}", old_lease))?;
+        let tb = encode(&self.inner.repo, data, self.lease)?;
+        let commit = self.push(old_lease, &tb)?;
         self.commit = Some(commit);
+        self.inner.observers.lease_renewed(self.lease);
         self.data.clear();
         self.data.extend_from_slice(data);
         Ok(())
     }
 
     /// Update the data and release the lease.
-    pub fn update_and_release(self, data: &[u8]) -> Result<()> {
-        let old_lease = self.lease;
-        let tb = encode(&self.inner.repo, &data, 0)?;
-        self.inner
-            .push(self.commit, &tb)?
-            .with_context(|| format!("Lost lease {}", old_lease))?;
+    pub fn update_and_release(mut self, data: &[u8]) -> LedgerResult<()> {
+        let tb = encode(&self.inner.repo, data, 0)?;
+        let commit = self.push(self.lease, &tb)?;
+        self.commit = Some(commit);
+        self.lease = 0;
         Ok(())
     }
 
     /// Release the lease. This will give an error if it was lost.
-    pub fn release(mut self) -> Result<()> {
+    pub fn release(mut self) -> LedgerResult<()> {
         self.release_internal()
     }
 
     /// Renew the lease.
-    pub fn renew(&mut self) -> Result<()> {
+    pub fn renew(&mut self) -> LedgerResult<()> {
         let old_lease = self.lease;
         self.lease = rand::thread_rng().gen();
         let tb = encode(&self.inner.repo, &self.data, self.lease)?;
-        let commit = self
-            .inner
-            .push(self.commit, &tb)?
-            .with_context(|| format!("Lost lease {}", old_lease))?;
+        let commit = self.push(old_lease, &tb)?;
         self.commit = Some(commit);
+        self.inner.observers.lease_renewed(self.lease);
         Ok(())
     }
 
-    fn release_internal(&mut self) -> Result<()> {
+    fn release_internal(&mut self) -> LedgerResult<()> {
         if self.lease == 0 {
             return Ok(());
         }
 
-        let old_lease = self.lease;
         let tb = encode(&self.inner.repo, &self.data, 0)?;
-        let commit = self
-            .inner
-            .push(self.commit, &tb)?
-            .with_context(|| format!("Lost lease {}", old_lease))?;
+        let commit = self.push(self.lease, &tb)?;
         self.commit = Some(commit);
         self.lease = 0;
         Ok(())
     }
+
+    /// Push a new state over our last commit. Losing the race means someone
+    /// else wrote while we believed we held `lease`.
+    fn push(&self, lease: u64, tb: &TreeBuilder) -> LedgerResult<ObjectId> {
+        match self.inner.push(self.commit, tb)? {
+            PushOutcome::Committed(commit) => Ok(commit),
+            PushOutcome::RaceLost { .. } => {
+                self.inner.observers.lease_lost(lease);
+                Err(LedgerError::LostLease { lease })
+            }
+        }
+    }
 }
 
 impl Drop for BlobGitLedgerGuard {
@@ -191,35 +300,34 @@ impl Drop for BlobGitLedgerGuard {
     }
 }
 
-fn decode(repo: &Repository, tree: Tree<'_>) -> Result<(Vec<u8>, u64)> {
-    let tree = tree.decode()?;
-    if tree.entries.len() > 1 {
-        anyhow::bail!("unexpected tree entries");
-    }
-    for entry in tree.entries.iter() {
-        let filename: &[u8] = entry.filename.as_ref();
-        let filename = hex::decode(filename)?;
-        let lease = u64::from_le_bytes(
-            filename
-                .try_into()
-                .map_err(|_| anyhow::anyhow!("invalid entry format"))?,
-        );
-        let blob = repo.find_object(entry.oid)?;
-        if blob.kind != Kind::Blob {
-            anyhow::bail!("not a blob");
-        }
-        return Ok((blob.data.to_vec(), lease));
+fn decode(repo: &Repository, tree: Tree<'_>) -> LedgerResult<(Vec<u8>, u64)> {
+    let corrupt = |what: &str| LedgerError::CorruptLedger(what.to_string());
+    let tree = tree.decode().context("decode tree")?;
+    let entry = match tree.entries.as_slice() {
+        [entry] => entry,
+        _ => return Err(corrupt("unexpected tree entries")),
+    };
+    let filename: &[u8] = entry.filename.as_ref();
+    let filename = hex::decode(filename).map_err(|_| corrupt("invalid entry format"))?;
+    let lease = u64::from_le_bytes(
+        filename
+            .try_into()
+            .map_err(|_| corrupt("invalid entry format"))?,
+    );
+    let blob = repo.find_object(entry.oid).context("find blob")?;
+    if blob.kind != Kind::Blob {
+        return Err(corrupt("not a blob"));
     }
-    unreachable!()
+    Ok((blob.data.to_vec(), lease))
 }
 
-fn encode(repo: &Repository, data: &[u8], lease: u64) -> Result<TreeBuilder> {
-    let blob = repo.write_blob(&data)?;
+fn encode(repo: &Repository, data: &[u8], lease: u64) -> LedgerResult<TreeBuilder> {
+    let blob = repo.write_blob(data).context("write blob")?;
     let mut tb = TreeBuilder::empty();
     tb.entries.push(tree::Entry {
         oid: blob.into(),
         mode: EntryMode::Blob,
-        filename: hex::encode(&lease.to_le_bytes()).into(),
+        filename: hex::encode(lease.to_le_bytes()).into(),
     });
     Ok(tb)
 }
@@ -228,24 +336,15 @@ fn encode(repo: &Repository, data: &[u8], lease: u64) -> Result<TreeBuilder> {
 mod tests {
     use super::*;
 
+    use crate::Metrics;
+
     macro_rules! setup {
         ($n:expr) => {{
             let tmp = tempdir::TempDir::new("unit.test").unwrap();
             let path = tmp.path();
-            let upstream_path = path.join("upstream");
-            gix::init_bare(&upstream_path).unwrap();
-
-            let make_ledger = |j|
-            {
-                let local_path = path.join(format!("local{}", j));
+            let make_ledger = |j| {
                 BlobGitLedger::new(
-                    GitLedger::new(
-                        local_path.clone(),
-                        upstream_path.to_string_lossy().to_string(),
-                        "origin".to_string(),
-                        "main".to_string(),
-                    )
-                        .unwrap(),
+                    crate::testing::ledger(path, j),
                     Duration::from_millis(50),
                     Duration::from_millis(500),
                 )
@@ -296,13 +395,16 @@ mod tests {
 
         let mut other = BlobGitLedgerGuard {
             inner: gledger.inner.clone(),
-            commit: gledger.commit.clone(),
+            commit: gledger.commit,
             data: gledger.data.clone(),
             lease: gledger.lease,
         };
         other.renew().unwrap();
 
-        assert!(gledger.renew().is_err());
+        assert!(matches!(
+            gledger.renew(),
+            Err(LedgerError::LostLease { .. })
+        ));
         other.renew().unwrap();
 
         assert_eq!(other.data(), b"foo");
@@ -324,6 +426,77 @@ mod tests {
         assert_eq!(gledger.data(), b"foo");
     }
 
+    #[test]
+    fn test_observer() {
+        let (_tmp, ledger) = setup!();
+        let metrics = Arc::new(Metrics::new());
+        let ledger = ledger.with_observer(metrics.clone());
+
+        let mut gledger = ledger.lock().unwrap();
+        gledger.renew().unwrap();
+        gledger.update(b"foo").unwrap();
+        std::mem::forget(gledger);
+
+        let mut gledger = ledger.lock().unwrap();
+        let mut other = BlobGitLedgerGuard {
+            inner: gledger.inner.clone(),
+            commit: gledger.commit,
+            data: gledger.data.clone(),
+            lease: gledger.lease,
+        };
+        other.renew().unwrap();
+        assert!(gledger.renew().is_err());
+        std::mem::forget(gledger);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.leases_acquired, 2);
+        assert_eq!(snapshot.leases_stolen, 1);
+        assert_eq!(snapshot.leases_renewed, 3);
+        assert_eq!(snapshot.leases_lost, 1);
+        assert_eq!(snapshot.races_lost, 1);
+        assert_eq!(snapshot.pushes, 6);
+        assert_eq!(snapshot.failed_pushes, 0);
+        assert!(snapshot.fetches >= 2);
+        assert_eq!(snapshot.lease_wait.count, 2);
+        assert!(snapshot.lease_wait.total >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_update_and_release() {
+        let (_tmp, ledger) = setup!();
+        let metrics = Arc::new(Metrics::new());
+        let ledger = ledger.with_observer(metrics.clone());
+
+        ledger.lock().unwrap().update_and_release(b"foo").unwrap();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.pushes, 2);
+        assert_eq!(snapshot.failed_pushes, 0);
+        assert_eq!(snapshot.races_lost, 0);
+        assert_eq!(snapshot.leases_lost, 0);
+
+        // Released, so the next lock need not wait for it to expire.
+        let start = Instant::now();
+        let gledger = ledger.lock().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(gledger.data(), b"foo");
+    }
+
+    #[test]
+    fn test_lock_deadline() {
+        let (_tmp, ledger) = setup!();
+
+        std::mem::forget(ledger.lock().unwrap());
+
+        let ledger = ledger
+            .with_retry_policy(RetryPolicy::default().with_deadline(Duration::from_millis(200)));
+        let start = Instant::now();
+        assert!(matches!(
+            ledger.lock(),
+            Err(LedgerError::RetriesExhausted { attempts: 0, .. })
+        ));
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
     #[test]
     fn test_lease() {
         let (_tmp, ledgers) = setup!(2);
@@ -0,0 +1,93 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Serialization format used by `TypedLedger` to store its value in a blob.
+/// JSON, bincode and CBOR implementations are available behind the `json`,
+/// `bincode` and `cbor` features.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T>;
+}
+
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, value: &T) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(value, &mut data)?;
+        Ok(data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        Ok(ciborium::de::from_reader(data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    #[allow(dead_code)]
+    fn roundtrip<C: Codec>(codec: C) {
+        let mut value = BTreeMap::new();
+        value.insert("apples".to_string(), vec![1u64, 2, 3]);
+        value.insert("pears".to_string(), vec![]);
+        let data = codec.encode(&value).unwrap();
+        let decoded: BTreeMap<String, Vec<u64>> = codec.decode(&data).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        roundtrip(Json);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_bincode() {
+        roundtrip(Bincode);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor() {
+        roundtrip(Cbor);
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::Context;
+use gix::actor::SignatureRef;
+use gix::date::Time;
+use gix::Repository;
+use gix_hash::ObjectId;
+use gix_ref::transaction::PreviousValue;
+
+use crate::signing::{sign_commit, Signer};
+
+/// A name and email address recorded on ledger commits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub email: String,
+}
+
+impl Identity {
+    pub fn new(name: impl Into<String>, email: impl Into<String>) -> Identity {
+        Identity {
+            name: name.into(),
+            email: email.into(),
+        }
+    }
+}
+
+/// Author, committer, message and time written on each ledger commit, and the
+/// key signing it, if any. Set a default for a ledger with
+/// `GitLedger::with_commit_info`, or override it for a single call with the
+/// `_with_info` variants of `push` and `update_with`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitInfo {
+    pub author: Identity,
+    pub committer: Identity,
+    pub message: String,
+
+    /// Time recorded for both author and committer; the current time if
+    /// `None`.
+    pub time: Option<Time>,
+
+    pub signer: Option<Signer>,
+}
+
+impl CommitInfo {
+    /// Use `identity` as both author and committer.
+    pub fn new(identity: Identity, message: impl Into<String>) -> CommitInfo {
+        CommitInfo {
+            author: identity.clone(),
+            committer: identity,
+            message: message.into(),
+            time: None,
+            signer: None,
+        }
+    }
+
+    pub fn with_author(mut self, author: Identity) -> CommitInfo {
+        self.author = author;
+        self
+    }
+
+    pub fn with_committer(mut self, committer: Identity) -> CommitInfo {
+        self.committer = committer;
+        self
+    }
+
+    pub fn with_message(mut self, message: impl Into<String>) -> CommitInfo {
+        self.message = message.into();
+        self
+    }
+
+    pub fn with_time(mut self, time: Time) -> CommitInfo {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn with_signer(mut self, signer: Signer) -> CommitInfo {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// The time to record now.
+    pub(crate) fn time(&self) -> Time {
+        self.time.unwrap_or_else(Time::now_local_or_utc)
+    }
+
+    pub(crate) fn author_signature(&self, time: Time) -> SignatureRef<'_> {
+        signature(&self.author, time)
+    }
+
+    pub(crate) fn committer_signature(&self, time: Time) -> SignatureRef<'_> {
+        signature(&self.committer, time)
+    }
+
+    /// Environment for a git subprocess so anything it records carries the
+    /// same identity and time as commits written with gix.
+    #[cfg(feature = "subprocess-push")]
+    pub(crate) fn apply(&self, cmd: &mut std::process::Command) {
+        cmd.env("GIT_AUTHOR_NAME", &self.author.name)
+            .env("GIT_AUTHOR_EMAIL", &self.author.email)
+            .env("GIT_COMMITTER_NAME", &self.committer.name)
+            .env("GIT_COMMITTER_EMAIL", &self.committer.email);
+        if let Some(time) = self.time {
+            let time = time.to_bstring().to_string();
+            cmd.env("GIT_AUTHOR_DATE", &time)
+                .env("GIT_COMMITTER_DATE", &time);
+        }
+    }
+}
+
+impl Default for CommitInfo {
+    fn default() -> CommitInfo {
+        CommitInfo::new(
+            Identity::new("git-ledger", "git-ledger@localhost"),
+            "A Commit In Time",
+        )
+    }
+}
+
+fn signature(identity: &Identity, time: Time) -> SignatureRef<'_> {
+    SignatureRef {
+        name: identity.name.as_str().into(),
+        email: identity.email.as_str().into(),
+        time,
+    }
+}
+
+/// Write a commit of `tree` over `parent` described by `info`, pointing
+/// `reference` at it.
+pub(crate) fn write_commit(
+    repo: &Repository,
+    reference: &str,
+    tree: ObjectId,
+    parent: Option<ObjectId>,
+    info: &CommitInfo,
+) -> anyhow::Result<ObjectId> {
+    let time = info.time();
+    if let Some(signer) = &info.signer {
+        let commit = gix_object::Commit {
+            tree,
+            parents: parent.into_iter().collect(),
+            author: info.author_signature(time).to_owned(),
+            committer: info.committer_signature(time).to_owned(),
+            encoding: None,
+            message: info.message.as_str().into(),
+            extra_headers: Vec::new(),
+        };
+        let commit = sign_commit(signer, commit).context("sign commit")?;
+        let id = repo.write_object(&commit).context("write commit")?.detach();
+        repo.reference(
+            reference,
+            id,
+            PreviousValue::Any,
+            format!("commit: {}", info.message),
+        )
+        .context("update reference")?;
+        return Ok(id);
+    }
+    Ok(repo
+        .commit_as(
+            info.committer_signature(time),
+            info.author_signature(time),
+            reference,
+            &info.message,
+            tree,
+            parent,
+        )
+        .context("commit to git")?
+        .detach())
+}
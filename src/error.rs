@@ -0,0 +1,216 @@
+use std::fmt;
+use std::time::Duration;
+
+use gix_hash::ObjectId;
+
+pub type LedgerResult<T> = std::result::Result<T, LedgerError>;
+
+/// Everything that can go wrong while reading or writing a ledger. Callers
+/// that retry on contention should match on `RaceLost` (and `LostLease` for
+/// `BlobGitLedger`); the remaining variants are not expected to go away by
+/// simply trying again.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LedgerError {
+    /// Another writer advanced the upstream branch from `expected` to `remote`
+    /// before our push landed.
+    RaceLost {
+        expected: Option<ObjectId>,
+        remote: Option<ObjectId>,
+    },
+
+    /// The remote could not be contacted, or the connection failed partway.
+    RemoteUnreachable(anyhow::Error),
+
+    /// The remote refused the push for a reason other than a lost race, such
+    /// as permissions or a server-side hook.
+    RemoteRejected { message: String },
+
+    /// Pushing to this remote url needs a transport this build lacks.
+    UnsupportedTransport(String),
+
+    /// The ledger branch does not contain data in the expected format.
+    CorruptLedger(String),
+
+    /// The remote tracking branch is not a descendant of the local branch, so
+    /// the local branch cannot be fast forwarded.
+    TrackingDiverged { local: ObjectId, remote: ObjectId },
+
+    /// The lease held by a `BlobGitLedgerGuard` was taken over by someone else.
+    LostLease { lease: u64 },
+
+    /// The `RetryPolicy` ran out of attempts or time before a push succeeded.
+    RetriesExhausted { attempts: u32, elapsed: Duration },
+
+    /// A fetched commit is unsigned, or not signed by one of the ledger's
+    /// `AllowedSigners`.
+    UnverifiedCommit { commit: ObjectId, reason: String },
+
+    /// A key was malformed, or clashed with the existing layout of the ledger.
+    InvalidKey { key: String, reason: &'static str },
+
+    /// The caller-supplied update function failed.
+    Update(anyhow::Error),
+
+    /// An operation on the local repository failed.
+    Repository(anyhow::Error),
+}
+
+/// Result of attempting to push a new ledger state. Losing a race is an
+/// expected outcome under contention rather than an error, but must not be
+/// silently ignored.
+#[must_use = "a push may lose a race, which must be handled"]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushOutcome {
+    /// The upstream branch now points at this new commit.
+    Committed(ObjectId),
+
+    /// The upstream branch was at `remote` instead of `expected`, so nothing
+    /// was written.
+    RaceLost {
+        expected: Option<ObjectId>,
+        remote: Option<ObjectId>,
+    },
+}
+
+impl PushOutcome {
+    pub fn is_committed(&self) -> bool {
+        matches!(self, PushOutcome::Committed(..))
+    }
+
+    /// The new commit, if the push succeeded.
+    pub fn committed(self) -> Option<ObjectId> {
+        match self {
+            PushOutcome::Committed(id) => Some(id),
+            PushOutcome::RaceLost { .. } => None,
+        }
+    }
+
+    /// Convert a lost race into `LedgerError::RaceLost`.
+    pub fn into_result(self) -> LedgerResult<ObjectId> {
+        match self {
+            PushOutcome::Committed(id) => Ok(id),
+            PushOutcome::RaceLost { expected, remote } => {
+                Err(LedgerError::RaceLost { expected, remote })
+            }
+        }
+    }
+}
+
+impl LedgerError {
+    /// Ledger wrappers report their own failures from inside the update
+    /// function they hand to `GitLedger`, which wraps them as `Update`; recover
+    /// the original.
+    pub(crate) fn unwrap_update(self) -> LedgerError {
+        match self {
+            LedgerError::Update(e) => match e.downcast::<LedgerError>() {
+                Ok(e) => e,
+                Err(e) => LedgerError::Update(e),
+            },
+            e => e,
+        }
+    }
+
+    /// A copy of this error to report to each of several callers. Wrapped
+    /// errors are flattened into their messages.
+    pub(crate) fn duplicate(&self) -> LedgerError {
+        let flatten = |e: &anyhow::Error| anyhow::anyhow!("{:#}", e);
+        match self {
+            LedgerError::RaceLost { expected, remote } => LedgerError::RaceLost {
+                expected: *expected,
+                remote: *remote,
+            },
+            LedgerError::RemoteUnreachable(e) => LedgerError::RemoteUnreachable(flatten(e)),
+            LedgerError::RemoteRejected { message } => LedgerError::RemoteRejected {
+                message: message.clone(),
+            },
+            LedgerError::UnsupportedTransport(url) => {
+                LedgerError::UnsupportedTransport(url.clone())
+            }
+            LedgerError::CorruptLedger(what) => LedgerError::CorruptLedger(what.clone()),
+            LedgerError::TrackingDiverged { local, remote } => LedgerError::TrackingDiverged {
+                local: *local,
+                remote: *remote,
+            },
+            LedgerError::LostLease { lease } => LedgerError::LostLease { lease: *lease },
+            LedgerError::RetriesExhausted { attempts, elapsed } => LedgerError::RetriesExhausted {
+                attempts: *attempts,
+                elapsed: *elapsed,
+            },
+            LedgerError::UnverifiedCommit { commit, reason } => LedgerError::UnverifiedCommit {
+                commit: *commit,
+                reason: reason.clone(),
+            },
+            LedgerError::InvalidKey { key, reason } => LedgerError::InvalidKey {
+                key: key.clone(),
+                reason,
+            },
+            LedgerError::Update(e) => LedgerError::Update(flatten(e)),
+            LedgerError::Repository(e) => LedgerError::Repository(flatten(e)),
+        }
+    }
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::RaceLost { expected, remote } => write!(
+                f,
+                "lost race: expected upstream at {}, found {}",
+                display_id(expected),
+                display_id(remote)
+            ),
+            LedgerError::RemoteUnreachable(..) => write!(f, "remote unreachable"),
+            LedgerError::RemoteRejected { message } => {
+                write!(f, "remote rejected push: {}", message)
+            }
+            LedgerError::UnsupportedTransport(url) => write!(
+                f,
+                "cannot push to {} without the subprocess-push feature",
+                url
+            ),
+            LedgerError::CorruptLedger(what) => write!(f, "corrupt ledger: {}", what),
+            LedgerError::TrackingDiverged { local, remote } => write!(
+                f,
+                "tracking branch at {} cannot fast forward local branch at {}",
+                remote, local
+            ),
+            LedgerError::LostLease { lease } => write!(f, "lost lease {}", lease),
+            LedgerError::RetriesExhausted { attempts, elapsed } => {
+                write!(f, "gave up after {} attempts in {:?}", attempts, elapsed)
+            }
+            LedgerError::UnverifiedCommit { commit, reason } => {
+                write!(f, "unverified commit {}: {}", commit, reason)
+            }
+            LedgerError::InvalidKey { key, reason } => {
+                write!(f, "invalid key {:?}: {}", key, reason)
+            }
+            LedgerError::Update(..) => write!(f, "update function failed"),
+            LedgerError::Repository(..) => write!(f, "local repository error"),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LedgerError::RemoteUnreachable(e)
+            | LedgerError::Update(e)
+            | LedgerError::Repository(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for LedgerError {
+    fn from(e: anyhow::Error) -> LedgerError {
+        LedgerError::Repository(e)
+    }
+}
+
+fn display_id(id: &Option<ObjectId>) -> String {
+    match id {
+        Some(id) => id.to_string(),
+        None => "nothing".to_string(),
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use gix::Repository;
+use gix_hash::ObjectId;
+use gix_object::tree::EntryMode;
+use gix_object::ObjectRef;
+use rand::Rng;
+
+const TMP_PREFIX: &str = "refs/tmp/";
+
+/// Loose objects younger than this are never pruned, as another process may
+/// have just written them and not yet pointed a ref at them.
+pub(crate) const PRUNE_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// A fresh temporary ref owned by this process,
+/// `refs/tmp/<host>/<pid>-<random>`.
+pub(crate) fn tmp_ref_name() -> String {
+    format!(
+        "{}{}/{}-{}",
+        TMP_PREFIX,
+        host(),
+        std::process::id(),
+        rand::thread_rng().gen::<u64>()
+    )
+}
+
+/// Delete temporary refs whose owning process has exited, returning how many
+/// were deleted.
+///
+/// Only refs written on this host are considered, as a process id means
+/// nothing elsewhere; refs from other hosts sharing the repository are left
+/// for those hosts to sweep. Hosts are told apart by hostname, so processes
+/// in separate PID namespaces that share both a hostname and the repository,
+/// such as containers started with the same hostname over one volume, must
+/// not run ledgers on it at the same time: one may sweep a ref the other is
+/// still pushing from.
+///
+/// Refs named before hosts were recorded, `refs/tmp/<pid>-<random>`, are
+/// treated as this host's, and those named before owners were recorded,
+/// `refs/tmp/tmp<random>`, are always deleted.
+pub(crate) fn sweep_tmp_refs(repo: &Repository) -> anyhow::Result<usize> {
+    let host = host();
+    let mut stale = Vec::new();
+    let references = repo.references().context("list refs")?;
+    for reference in references.prefixed(TMP_PREFIX).context("list tmp refs")? {
+        let reference = reference.map_err(|e| anyhow::anyhow!(e))?;
+        let name = reference.name().as_bstr().to_string();
+        let owner = match name[TMP_PREFIX.len()..].split_once('/') {
+            Some((owner_host, _)) if owner_host != host => continue,
+            Some((_, owner)) => owner,
+            None => &name[TMP_PREFIX.len()..],
+        };
+        if !owner_alive(owner) {
+            stale.push(reference);
+        }
+    }
+
+    let swept = stale.len();
+    for reference in stale {
+        log::trace!("sweep_tmp_refs: deleting {}", reference.name().as_bstr());
+        reference.delete().context("delete tmp ref")?;
+    }
+    Ok(swept)
+}
+
+fn owner_alive(name: &str) -> bool {
+    match name.split_once('-').map(|(pid, _)| pid.parse::<u32>()) {
+        Some(Ok(pid)) => process_alive(pid),
+        _ => false,
+    }
+}
+
+/// This host's name as a single ref name component, with anything but ASCII
+/// letters, digits, `-` and `_` replaced by `_`.
+fn host() -> &'static str {
+    static HOST: OnceLock<String> = OnceLock::new();
+    HOST.get_or_init(|| {
+        let host: String = hostname()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if host.is_empty() {
+            "unknown".to_string()
+        } else {
+            host
+        }
+    })
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return true;
+    }
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists and we may signal it.
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to tell, assume other processes are still running.
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+/// Delete loose objects older than `grace` that no ref reaches, returning how
+/// many were deleted. Packs are left as they are.
+pub(crate) fn prune(repo: &Repository, grace: Duration) -> anyhow::Result<usize> {
+    let reachable = reachable(repo)?;
+    let cutoff = SystemTime::now()
+        .checked_sub(grace)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut pruned = 0;
+    for fanout in std::fs::read_dir(repo.path().join("objects")).context("read objects")? {
+        let fanout = fanout.context("read objects")?;
+        let prefix = fanout.file_name().to_string_lossy().to_string();
+        if prefix.len() != 2 || !fanout.file_type()?.is_dir() {
+            continue;
+        }
+        for object in std::fs::read_dir(fanout.path()).context("read objects")? {
+            let object = object.context("read objects")?;
+            let name = format!("{}{}", prefix, object.file_name().to_string_lossy());
+            let Ok(id) = ObjectId::from_hex(name.as_bytes()) else {
+                continue;
+            };
+            if reachable.contains(&id) || object.metadata()?.modified()? > cutoff {
+                continue;
+            }
+            log::trace!("prune: deleting {}", id);
+            std::fs::remove_file(object.path()).context("delete object")?;
+            pruned += 1;
+        }
+        // Only succeeds once the directory is empty.
+        let _ = std::fs::remove_dir(fanout.path());
+    }
+    Ok(pruned)
+}
+
+/// Every object reachable from a ref, stopping at the shallow boundary.
+fn reachable(repo: &Repository) -> anyhow::Result<HashSet<ObjectId>> {
+    let shallow = repo.shallow_commits().context("read shallow")?;
+    let mut pending = Vec::new();
+    let references = repo.references().context("list refs")?;
+    for reference in references.all().context("list refs")? {
+        let reference = reference.map_err(|e| anyhow::anyhow!(e))?;
+        if let Some(id) = reference.try_id() {
+            pending.push(id.detach());
+        }
+    }
+
+    let mut seen = HashSet::new();
+    while let Some(id) = pending.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let object = repo.find_object(id).context("find object")?;
+        let decoded = ObjectRef::from_bytes(object.kind, &object.data).context("decode object")?;
+        match decoded {
+            ObjectRef::Commit(commit) => {
+                pending.push(commit.tree());
+                // Parents beyond the shallow boundary are not here to walk.
+                if shallow
+                    .as_ref()
+                    .is_none_or(|s| s.binary_search(&id).is_err())
+                {
+                    pending.extend(commit.parents());
+                }
+            }
+            ObjectRef::Tree(tree) => pending.extend(
+                tree.entries
+                    .iter()
+                    .filter(|entry| entry.mode != EntryMode::Commit)
+                    .map(|entry| entry.oid.to_owned()),
+            ),
+            ObjectRef::Tag(tag) => pending.push(tag.target()),
+            ObjectRef::Blob(..) => {}
+        }
+    }
+    Ok(seen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gix_ref::transaction::PreviousValue;
+
+    fn age_objects(repo: &Repository) {
+        let old = SystemTime::now() - 2 * PRUNE_GRACE;
+        for fanout in std::fs::read_dir(repo.path().join("objects")).unwrap() {
+            let fanout = fanout.unwrap();
+            if fanout.file_name().len() != 2 {
+                continue;
+            }
+            for object in std::fs::read_dir(fanout.path()).unwrap() {
+                std::fs::File::options()
+                    .write(true)
+                    .open(object.unwrap().path())
+                    .unwrap()
+                    .set_modified(old)
+                    .unwrap();
+            }
+        }
+    }
+
+    // Telling whether a process has exited needs `kill`.
+    #[cfg(unix)]
+    #[test]
+    fn test_sweep_tmp_refs() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let repo = gix::init_bare(tmp.path().join("repo")).unwrap();
+        let blob = repo.write_blob(b"tmp").unwrap().detach();
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let ours = tmp_ref_name();
+        let names = [
+            ours.clone(),
+            format!("refs/tmp/{}/{}-1", host(), child.id()),
+            "refs/tmp/tmp1234".to_string(),
+            format!("refs/tmp/{}-1", child.id()),
+            format!("refs/tmp/elsewhere-{}/{}-1", host(), child.id()),
+        ];
+        for name in &names {
+            repo.reference(name.as_str(), blob, PreviousValue::Any, "test")
+                .unwrap();
+        }
+
+        // Only refs from this host are swept, whatever their process ids.
+        assert_eq!(sweep_tmp_refs(&repo).unwrap(), 3);
+        for (name, kept) in names.iter().zip([true, false, false, false, true]) {
+            let found = repo.try_find_reference(name.as_str()).unwrap();
+            assert_eq!(found.is_some(), kept, "{}", name);
+        }
+        assert_eq!(sweep_tmp_refs(&repo).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_prune() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let repo = gix::init_bare(tmp.path().join("repo")).unwrap();
+        let kept = repo.write_blob(b"kept").unwrap().detach();
+        let mut tree = gix_object::Tree::empty();
+        tree.entries.push(gix_object::tree::Entry {
+            oid: kept,
+            mode: EntryMode::Blob,
+            filename: "kept".into(),
+        });
+        let tree = repo.write_object(&tree).unwrap().detach();
+        let commit =
+            crate::commit::write_commit(&repo, "refs/heads/main", tree, None, &Default::default())
+                .unwrap();
+        let garbage = repo.write_blob(b"garbage").unwrap().detach();
+
+        // Fresh objects survive the grace period.
+        assert_eq!(prune(&repo, PRUNE_GRACE).unwrap(), 0);
+
+        age_objects(&repo);
+        assert_eq!(prune(&repo, PRUNE_GRACE).unwrap(), 1);
+        assert!(repo.find_object(garbage).is_err());
+        for id in [kept, tree, commit] {
+            repo.find_object(id).unwrap();
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::Context;
+use gix::date::Time;
+use gix::object::Kind;
+use gix::traverse::commit::Sorting;
+use gix::Repository;
+use gix_hash::ObjectId;
+
+use crate::error::{LedgerError, LedgerResult};
+
+/// Which part of a ledger's history `GitLedger::history` walks.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HistoryOptions {
+    /// Stop before this commit; neither it nor its ancestors are returned.
+    pub stop_at_commit: Option<ObjectId>,
+
+    /// Stop once commits are older than this.
+    pub stop_at_time: Option<Time>,
+
+    /// Follow only the first parent of each commit.
+    pub first_parent_only: bool,
+}
+
+impl HistoryOptions {
+    pub fn with_stop_at_commit(mut self, id: ObjectId) -> HistoryOptions {
+        self.stop_at_commit = Some(id);
+        self
+    }
+
+    pub fn with_stop_at_time(mut self, time: Time) -> HistoryOptions {
+        self.stop_at_time = Some(time);
+        self
+    }
+
+    pub fn with_first_parent_only(mut self) -> HistoryOptions {
+        self.first_parent_only = true;
+        self
+    }
+}
+
+/// One past state of a ledger.
+#[derive(Debug)]
+pub struct HistoryEntry<'repo> {
+    pub id: ObjectId,
+
+    /// When the state was committed.
+    pub time: Time,
+    pub message: String,
+    pub tree: gix::Tree<'repo>,
+}
+
+/// Iterator over the states of a ledger, newest first.
+pub struct History<'repo> {
+    repo: &'repo Repository,
+    walk: Option<gix::revision::Walk<'repo>>,
+    stop_at_time: Option<Time>,
+}
+
+impl<'repo> History<'repo> {
+    pub(crate) fn new(
+        repo: &'repo Repository,
+        tip: Option<ObjectId>,
+        options: HistoryOptions,
+    ) -> LedgerResult<History<'repo>> {
+        let tip = match tip {
+            Some(tip) if Some(tip) != options.stop_at_commit => tip,
+            _ => {
+                return Ok(History {
+                    repo,
+                    walk: None,
+                    stop_at_time: None,
+                })
+            }
+        };
+
+        let sorting = match options.stop_at_time {
+            Some(time) => Sorting::ByCommitTimeNewestFirstCutoffOlderThan {
+                time_in_seconds_since_epoch: time.seconds(),
+            },
+            None => Sorting::ByCommitTimeNewestFirst,
+        };
+        let mut platform = repo.rev_walk([tip]).sorting(sorting);
+        if options.first_parent_only {
+            platform = platform.first_parent_only();
+        }
+        let stop_at_commit = options.stop_at_commit;
+        let walk = platform
+            .selected(move |id| Some(id.to_owned()) != stop_at_commit)
+            .context("walk history")?;
+
+        Ok(History {
+            repo,
+            walk: Some(walk),
+            stop_at_time: options.stop_at_time,
+        })
+    }
+
+    fn entry(&self, id: ObjectId) -> LedgerResult<HistoryEntry<'repo>> {
+        let object = self.repo.find_object(id).context("find commit")?;
+        if object.kind != Kind::Commit {
+            return Err(LedgerError::CorruptLedger(format!(
+                "expected commit at {}, found {}",
+                id, object.kind
+            )));
+        }
+        let commit = object.into_commit();
+        let (time, message) = {
+            let decoded = commit.decode().context("decode commit")?;
+            (
+                decoded.committer.time,
+                String::from_utf8_lossy(decoded.message).into_owned(),
+            )
+        };
+        let tree = commit.tree().context("commit tree")?;
+        Ok(HistoryEntry {
+            id,
+            time,
+            message,
+            tree,
+        })
+    }
+}
+
+impl<'repo> Iterator for History<'repo> {
+    type Item = LedgerResult<HistoryEntry<'repo>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = match self.walk.as_mut()?.next()? {
+            Ok(id) => id.detach(),
+            Err(e) => {
+                self.walk = None;
+                return Some(Err(LedgerError::Repository(
+                    anyhow::Error::new(e).context("walk history"),
+                )));
+            }
+        };
+        let entry = self.entry(id);
+        match (&entry, self.stop_at_time) {
+            // The walk may yield one commit past the cutoff before it stops.
+            (Ok(entry), Some(time)) if entry.time.seconds() < time.seconds() => {
+                self.walk = None;
+                None
+            }
+            _ => Some(entry),
+        }
+    }
+}
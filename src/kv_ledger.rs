@@ -0,0 +1,470 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use gix::object::Kind;
+use gix::Repository;
+use gix_hash::ObjectId;
+use gix_object::{
+    tree::{self, EntryMode},
+    Tree as TreeBuilder,
+};
+
+use crate::{GitLedger, LedgerError, LedgerResult, PushOutcome};
+
+/// `GitLedger` used as a key-value store. Keys are slash-separated paths
+/// stored as blobs in nested trees, so `teams/a/owner` is the blob `owner` in
+/// tree `a` in tree `teams`. Updates go through a `KvTxn` and rewrite only the
+/// trees on the paths of changed keys.
+#[derive(Clone, Debug)]
+pub struct KvLedger {
+    inner: GitLedger,
+}
+
+/// Changes to apply to a `KvLedger` in one commit. Reads see the ledger state
+/// the transaction started from plus its own writes.
+pub struct KvTxn<'a> {
+    repo: &'a Repository,
+    root: Option<ObjectId>,
+    writes: BTreeMap<Vec<String>, Option<Vec<u8>>>,
+    error: Option<LedgerError>,
+}
+
+impl KvLedger {
+    pub fn new(inner: GitLedger) -> KvLedger {
+        KvLedger { inner }
+    }
+
+    pub fn inner(&self) -> &GitLedger {
+        &self.inner
+    }
+
+    /// The latest upstream value of `key`.
+    pub fn get(&self, key: &str) -> LedgerResult<Option<Vec<u8>>> {
+        let path = parse_key(key)?;
+        let root = self.fetch_root()?;
+        lookup(&self.inner.repo, root, &path)
+    }
+
+    /// All upstream keys at or below the directory `prefix`, in sorted order.
+    /// An empty prefix lists every key.
+    pub fn list(&self, prefix: &str) -> LedgerResult<Vec<String>> {
+        let path = match prefix.trim_end_matches('/') {
+            "" => Vec::new(),
+            prefix => parse_key(prefix)?,
+        };
+        let mut tree = self.fetch_root()?;
+        for name in &path {
+            tree = match tree {
+                Some(id) => match find_entry(&self.inner.repo, id, name)? {
+                    Some(entry) if entry.mode == EntryMode::Tree => Some(entry.oid),
+                    _ => None,
+                },
+                None => None,
+            };
+        }
+
+        let mut keys = Vec::new();
+        if let Some(tree) = tree {
+            collect_keys(&self.inner.repo, tree, &mut path.join("/"), &mut keys)?;
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    pub fn update_once<F, E>(&self, f: F) -> LedgerResult<PushOutcome>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnOnce(&mut KvTxn<'_>) -> std::result::Result<(), E>,
+    {
+        self.inner
+            .update_once_with(|repo, old| apply(repo, old.map(|(_, tree)| tree.id), f))
+            .map_err(LedgerError::unwrap_update)
+    }
+
+    /// Repeatedly run `f` in a transaction over the latest upstream state
+    /// until its changes are pushed, returning the new commit.
+    pub fn update<F, E>(&self, mut f: F) -> LedgerResult<ObjectId>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnMut(&mut KvTxn<'_>) -> std::result::Result<(), E>,
+    {
+        self.inner
+            .update_with(|repo, old| apply(repo, old.map(|(_, tree)| tree.id), &mut f))
+            .map_err(LedgerError::unwrap_update)
+    }
+
+    fn fetch_root(&self) -> LedgerResult<Option<ObjectId>> {
+        Ok(self.inner.fetch()?.map(|(_, tree)| tree.id))
+    }
+}
+
+impl<'a> KvTxn<'a> {
+    pub fn get(&self, key: &str) -> LedgerResult<Option<Vec<u8>>> {
+        let path = parse_key(key)?;
+        match self.writes.get(&path) {
+            Some(value) => Ok(value.clone()),
+            None => lookup(self.repo, self.root, &path),
+        }
+    }
+
+    pub fn put(&mut self, key: &str, value: impl Into<Vec<u8>>) {
+        self.write(key, Some(value.into()));
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.write(key, None);
+    }
+
+    /// Errors are held until the transaction is applied, so that a bad key
+    /// fails the whole update.
+    fn write(&mut self, key: &str, value: Option<Vec<u8>>) {
+        match parse_key(key) {
+            Ok(path) => {
+                self.writes.insert(path, value);
+            }
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+}
+
+pub(crate) fn apply<F, E>(
+    repo: &Repository,
+    root: Option<ObjectId>,
+    f: F,
+) -> LedgerResult<TreeBuilder>
+where
+    E: Into<anyhow::Error>,
+    F: FnOnce(&mut KvTxn<'_>) -> std::result::Result<(), E>,
+{
+    let mut txn = KvTxn {
+        repo,
+        root,
+        writes: BTreeMap::new(),
+        error: None,
+    };
+    f(&mut txn).map_err(|e| LedgerError::Update(e.into()))?;
+    if let Some(e) = txn.error {
+        return Err(e);
+    }
+
+    let writes: Vec<_> = txn
+        .writes
+        .iter()
+        .map(|(path, value)| (path.as_slice(), value.as_deref()))
+        .collect();
+    Ok(TreeBuilder {
+        entries: rewrite(repo, root, &writes, "")?,
+    })
+}
+
+/// Apply `writes`, with paths relative to `tree`, returning the new entries.
+/// Entries for names no write touches are carried over unchanged.
+fn rewrite(
+    repo: &Repository,
+    tree: Option<ObjectId>,
+    writes: &[(&[String], Option<&[u8]>)],
+    dir: &str,
+) -> LedgerResult<Vec<tree::Entry>> {
+    let mut entries: BTreeMap<Vec<u8>, tree::Entry> = match tree {
+        Some(id) => read_tree(repo, id)?
+            .entries
+            .into_iter()
+            .map(|entry| (entry.filename.to_vec(), entry))
+            .collect(),
+        None => BTreeMap::new(),
+    };
+
+    // Writes are sorted by path, so those under the same name are adjacent.
+    let mut rest = writes;
+    while let Some(((path, _), _)) = rest.split_first() {
+        let name = &path[0];
+        let len = rest.iter().take_while(|(path, _)| &path[0] == name).count();
+        let (group, tail) = rest.split_at(len);
+        rest = tail;
+
+        let key = format!("{}{}", dir, name);
+        let existing = entries.remove(name.as_bytes());
+        let is_tree = existing.as_ref().map(|entry| entry.mode == EntryMode::Tree);
+
+        if let [(path, value)] = group {
+            if path.len() == 1 {
+                match (value, is_tree) {
+                    (_, Some(true)) => {
+                        return Err(LedgerError::InvalidKey {
+                            key,
+                            reason: "a directory of keys already has this name",
+                        })
+                    }
+                    (Some(value), _) => {
+                        let blob = repo.write_blob(value).context("write blob")?;
+                        entries.insert(
+                            name.as_bytes().to_vec(),
+                            tree::Entry {
+                                oid: blob.into(),
+                                mode: EntryMode::Blob,
+                                filename: name.as_str().into(),
+                            },
+                        );
+                    }
+                    (None, _) => {}
+                }
+                continue;
+            }
+        }
+
+        if group.iter().any(|(path, _)| path.len() == 1) || is_tree == Some(false) {
+            return Err(LedgerError::InvalidKey {
+                key,
+                reason: "used both as a key and as a directory of keys",
+            });
+        }
+        let group: Vec<_> = group
+            .iter()
+            .map(|(path, value)| (&path[1..], *value))
+            .collect();
+        let children = rewrite(
+            repo,
+            existing.map(|entry| entry.oid),
+            &group,
+            &format!("{}/", key),
+        )?;
+        // Git does not store empty trees, so a directory goes with its last key.
+        if !children.is_empty() {
+            let subtree = repo
+                .write_object(&TreeBuilder { entries: children })
+                .context("write tree")?;
+            entries.insert(
+                name.as_bytes().to_vec(),
+                tree::Entry {
+                    oid: subtree.detach(),
+                    mode: EntryMode::Tree,
+                    filename: name.as_str().into(),
+                },
+            );
+        }
+    }
+
+    // Git orders tree entries as though directory names end with a slash.
+    let mut entries: Vec<_> = entries.into_values().collect();
+    entries.sort();
+    Ok(entries)
+}
+
+fn lookup(
+    repo: &Repository,
+    root: Option<ObjectId>,
+    path: &[String],
+) -> LedgerResult<Option<Vec<u8>>> {
+    let mut tree = match root {
+        Some(root) => root,
+        None => return Ok(None),
+    };
+    for (i, name) in path.iter().enumerate() {
+        let entry = match find_entry(repo, tree, name)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        match (entry.mode, i + 1 == path.len()) {
+            (EntryMode::Tree, false) => tree = entry.oid,
+            (EntryMode::Blob | EntryMode::BlobExecutable, true) => {
+                let blob = repo.find_object(entry.oid).context("find blob")?;
+                return Ok(Some(blob.data.to_vec()));
+            }
+            _ => return Ok(None),
+        }
+    }
+    Ok(None)
+}
+
+fn collect_keys(
+    repo: &Repository,
+    tree: ObjectId,
+    dir: &mut String,
+    keys: &mut Vec<String>,
+) -> LedgerResult<()> {
+    for entry in read_tree(repo, tree)?.entries {
+        let len = dir.len();
+        if !dir.is_empty() {
+            dir.push('/');
+        }
+        dir.push_str(&entry.filename.to_string());
+        match entry.mode {
+            EntryMode::Tree => collect_keys(repo, entry.oid, dir, keys)?,
+            EntryMode::Blob | EntryMode::BlobExecutable => keys.push(dir.clone()),
+            _ => {}
+        }
+        dir.truncate(len);
+    }
+    Ok(())
+}
+
+fn find_entry(repo: &Repository, tree: ObjectId, name: &str) -> LedgerResult<Option<tree::Entry>> {
+    Ok(read_tree(repo, tree)?
+        .entries
+        .into_iter()
+        .find(|entry| entry.filename == name))
+}
+
+pub(crate) fn read_tree(repo: &Repository, id: ObjectId) -> LedgerResult<TreeBuilder> {
+    let object = repo.find_object(id).context("find tree")?;
+    if object.kind != Kind::Tree {
+        return Err(LedgerError::CorruptLedger(format!(
+            "expected tree at {}, found {}",
+            id, object.kind
+        )));
+    }
+    let tree = gix_object::TreeRef::from_bytes(&object.data).context("decode tree")?;
+    Ok(tree.into())
+}
+
+/// Split a key into path components, rejecting any git cannot store as a
+/// tree entry.
+fn parse_key(key: &str) -> LedgerResult<Vec<String>> {
+    let invalid = |reason| LedgerError::InvalidKey {
+        key: key.to_string(),
+        reason,
+    };
+    key.split('/')
+        .map(|name| match name {
+            "" => Err(invalid("empty path component")),
+            "." | ".." | ".git" => Err(invalid("reserved path component")),
+            name if name.contains('\0') => Err(invalid("contains a NUL byte")),
+            name => Ok(name.to_string()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+
+    fn make_ledger(path: &std::path::Path) -> KvLedger {
+        KvLedger::new(testing::ledger_on(path, "local", "main"))
+    }
+
+    fn root_tree(ledger: &KvLedger) -> TreeBuilder {
+        let root = ledger.fetch_root().unwrap().unwrap();
+        read_tree(&ledger.inner.repo, root).unwrap()
+    }
+
+    #[test]
+    fn test_kv_ledger() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger = make_ledger(tmp.path());
+        assert_eq!(ledger.get("teams/a/owner").unwrap(), None);
+        assert!(ledger.list("").unwrap().is_empty());
+
+        ledger
+            .update(|txn| {
+                txn.put("teams/a/owner", "alice");
+                txn.put("teams/a/members/bob", "");
+                txn.put("teams/b/owner", "carol");
+                txn.put("version", "1");
+                assert_eq!(txn.get("teams/a/owner")?, Some(b"alice".to_vec()));
+                anyhow::Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(
+            ledger.get("teams/a/owner").unwrap(),
+            Some(b"alice".to_vec())
+        );
+        assert_eq!(ledger.get("teams/a").unwrap(), None);
+        assert_eq!(ledger.get("teams/a/owner/x").unwrap(), None);
+        assert_eq!(
+            ledger.list("teams/a").unwrap(),
+            ["teams/a/members/bob", "teams/a/owner"]
+        );
+        assert_eq!(ledger.list("").unwrap().len(), 4);
+
+        let find = |tree: &TreeBuilder, name: &str| {
+            tree.entries
+                .iter()
+                .find(|entry| entry.filename == name)
+                .map(|entry| entry.oid)
+        };
+        let teams_before = read_tree(
+            &ledger.inner.repo,
+            find(&root_tree(&ledger), "teams").unwrap(),
+        )
+        .unwrap();
+
+        ledger
+            .update(|txn| {
+                txn.delete("teams/a/members/bob");
+                txn.put("version", "2");
+                anyhow::Ok(())
+            })
+            .unwrap();
+
+        // Only trees on the changed paths are rewritten, and emptied
+        // directories disappear.
+        let teams_after = read_tree(
+            &ledger.inner.repo,
+            find(&root_tree(&ledger), "teams").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(find(&teams_before, "b"), find(&teams_after, "b"));
+        assert_ne!(find(&teams_before, "a"), find(&teams_after, "a"));
+        assert_eq!(ledger.list("teams/a").unwrap(), ["teams/a/owner"]);
+        assert_eq!(ledger.get("version").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_entry_order() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger = make_ledger(tmp.path());
+        ledger
+            .update(|txn| {
+                for key in ["a0", "a/x", "a.b", "a-c"] {
+                    txn.put(key, key);
+                }
+                anyhow::Ok(())
+            })
+            .unwrap();
+
+        let names: Vec<_> = root_tree(&ledger)
+            .entries
+            .iter()
+            .map(|entry| entry.filename.to_string())
+            .collect();
+        assert_eq!(names, ["a-c", "a.b", "a", "a0"]);
+        assert_eq!(ledger.get("a/x").unwrap(), Some(b"a/x".to_vec()));
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger = make_ledger(tmp.path());
+        ledger
+            .update(|txn| {
+                txn.put("a/b", "");
+                anyhow::Ok(())
+            })
+            .unwrap();
+
+        for key in ["", "a//b", "a/../b", ".git/config"] {
+            assert!(matches!(
+                ledger.update(|txn| {
+                    txn.put(key, "");
+                    anyhow::Ok(())
+                }),
+                Err(LedgerError::InvalidKey { .. })
+            ));
+        }
+        for key in ["a", "a/b/c"] {
+            assert!(matches!(
+                ledger.update(|txn| {
+                    txn.put(key, "");
+                    anyhow::Ok(())
+                }),
+                Err(LedgerError::InvalidKey { .. })
+            ));
+        }
+        assert_eq!(ledger.list("").unwrap(), ["a/b"]);
+    }
+}
@@ -1,15 +1,32 @@
-use std::path::PathBuf;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::Context;
 use gix::object::Kind;
 use gix::progress::Discard as DiscardProgress;
+use gix::remote::fetch::{Shallow, Tags};
 use gix::remote::Direction;
 use gix::{Commit, Repository};
 use gix_hash::ObjectId;
 use gix_object::Tree as TreeBuilder;
-use rand::Rng;
+use gix_ref::transaction::PreviousValue;
+use gix_ref::Target;
 
+use crate::backend::Backend;
+use crate::commit::{write_commit, CommitInfo};
+use crate::error::{LedgerError, LedgerResult, PushOutcome};
+use crate::gc::{prune, sweep_tmp_refs, tmp_ref_name, PRUNE_GRACE};
+use crate::history::{History, HistoryOptions};
+use crate::lock::lock_refs;
+use crate::observer::{Observer, Observers};
+use crate::push::{push_commit, update_ref, RefPush};
+use crate::quorum::{fetch_replicas, push_replicas, Replica, MAX_REPLICAS};
+use crate::retry::RetryPolicy;
+use crate::signing::{verify_commits, AllowedSigners};
 use crate::util::*;
+use crate::watch::Watch;
 
 /// Manages a monotonic ledger stored as a root tree on a branch in a local git
 /// repository, and synchronizes to upstream. Ledgers may be updated using the
@@ -17,14 +34,105 @@ use crate::util::*;
 /// version) and repeatedly fetches the upstream state, applies the function,
 /// then attempts to push a commit containing the new tree, or the more general
 /// API provided by fetch / push.
+///
+/// A ledger created with `local` has no upstream: the branch in the local
+/// repository is the ledger, fetch only reads it, and push is a
+/// compare-and-swap on the branch ref. One created with `replicated` is kept
+/// on several remotes, and a state counts once a majority of them hold it.
+/// One created with `on_backend` keeps its branch in a `Backend`, such as a
+/// `MemoryBackend` for tests.
 #[derive(Clone, Debug)]
 pub struct GitLedger {
     pub repo: Repository,
     local_path: PathBuf,
     branch_ref: String,
-    tracking_ref: String,
-    remote_name: String,
+    upstream: Upstream,
+    commit_info: CommitInfo,
+    retry_policy: RetryPolicy,
+    allowed_signers: Option<AllowedSigners>,
+    divergence_policy: DivergencePolicy,
+    fetch_depth: Option<NonZeroU32>,
+    synced: Arc<Mutex<Option<Instant>>>,
+    pub(crate) observers: Observers,
+}
+
+/// A commit written for a push, kept from `gc` until dropped by a tmp ref of
+/// its own, so concurrent pushes from clones of a ledger never share one.
+#[derive(Debug)]
+pub(crate) struct Pending<'repo> {
+    repo: &'repo Repository,
     tmp_ref: String,
+    pub(crate) id: ObjectId,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        let deleted = self
+            .repo
+            .find_reference(self.tmp_ref.as_str())
+            .map_err(anyhow::Error::from)
+            .and_then(|reference| reference.delete().map_err(anyhow::Error::from));
+        if let Err(e) = deleted {
+            log::warn!("deleting {}: {:#}", self.tmp_ref, e);
+        }
+    }
+}
+
+/// How up to date `GitLedger::fetch_with` must be.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Freshness {
+    /// Fetch from upstream every time, as `fetch` does.
+    #[default]
+    Latest,
+
+    /// Read the local branch if this ledger, or a clone of it, fetched at
+    /// most this long ago, and fetch otherwise.
+    MaxAge(Duration),
+
+    /// Read the local branch without contacting upstream.
+    LocalOnly,
+}
+
+/// Ledger state read by `GitLedger::fetch_with`.
+#[derive(Debug)]
+pub struct Snapshot<'repo> {
+    pub state: Option<(Commit<'repo>, gix::Tree<'repo>)>,
+
+    /// Time since the state was fetched from upstream, or `None` if it was
+    /// not fetched by this ledger or a clone of it, so its age is unknown.
+    pub age: Option<Duration>,
+}
+
+/// What fetch does when the upstream branch no longer contains the local one,
+/// as after a force push. Discarded tips are kept locally under
+/// `refs/ledger-backup/<branch>/<commit>`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DivergencePolicy {
+    /// Fail with `TrackingDiverged`, leaving the local branch alone.
+    #[default]
+    Refuse,
+
+    /// Move the local branch to the upstream one, backing up the local tip.
+    AdoptRemote,
+
+    /// Push the local branch back over the upstream one, backing up the
+    /// upstream tip. Replicated ledgers refuse instead, as their replicas
+    /// only fast forward.
+    RepushLocal,
+}
+
+#[derive(Clone, Debug)]
+enum Upstream {
+    Remote {
+        name: String,
+        tracking_ref: String,
+    },
+    Quorum(Vec<Replica>),
+    Local,
+    Backend {
+        backend: Arc<dyn Backend>,
+        tracking_ref: String,
+    },
 }
 
 impl GitLedger {
@@ -33,23 +141,265 @@ impl GitLedger {
         remote_spec: String,
         remote_name: String,
         branch_name: String,
-    ) -> Result<GitLedger> {
-        let mut repo = init_repo(&local_path, &remote_spec, &remote_name, true)?;
+    ) -> LedgerResult<GitLedger> {
+        let repo = init_repo(&local_path, &remote_spec, &remote_name, true)?;
+        let tracking_ref = format!("remotes/{}/{}", &remote_name, &branch_name);
+        Ok(GitLedger::with_upstream(
+            repo,
+            local_path,
+            branch_name,
+            Upstream::Remote {
+                name: remote_name,
+                tracking_ref,
+            },
+        ))
+    }
+
+    /// A ledger replicated to every remote in `remotes`, given as
+    /// `(remote_name, remote_spec)` pairs. A push is committed once a majority
+    /// of the remotes accept it, and fetch reads the newest state a majority
+    /// holds, bringing remotes that fell behind up to date. Both fail with
+    /// `RemoteUnreachable` while fewer than a majority can be reached.
+    ///
+    /// Writes that reach only a minority are taken back, and reported as
+    /// `RaceLost` even if the majority state is still `expected`.
+    pub fn replicated(
+        local_path: PathBuf,
+        remotes: Vec<(String, String)>,
+        branch_name: String,
+    ) -> LedgerResult<GitLedger> {
+        if remotes.is_empty() || remotes.len() > MAX_REPLICAS {
+            return Err(LedgerError::Repository(anyhow::anyhow!(
+                "replicated ledgers need 1 to {} remotes, got {}",
+                MAX_REPLICAS,
+                remotes.len()
+            )));
+        }
+        let mut replicas = Vec::new();
+        for (remote_name, remote_spec) in remotes {
+            init_repo(&local_path, &remote_spec, &remote_name, true)?;
+            replicas.push(Replica {
+                tracking_ref: format!("remotes/{}/{}", &remote_name, &branch_name),
+                name: remote_name,
+            });
+        }
+        let repo = open_or_init(&local_path)?;
+        Ok(GitLedger::with_upstream(
+            repo,
+            local_path,
+            branch_name,
+            Upstream::Quorum(replicas),
+        ))
+    }
+
+    /// A ledger kept on `branch_name` in the repository at `local_path`,
+    /// creating a bare repository if there is none, with no remote.
+    pub fn local(local_path: PathBuf, branch_name: String) -> LedgerResult<GitLedger> {
+        let repo = open_or_init(&local_path)?;
+        Ok(GitLedger::with_upstream(
+            repo,
+            local_path,
+            branch_name,
+            Upstream::Local,
+        ))
+    }
+
+    /// A ledger on `branch_name` in `repo`, already opened at `local_path`
+    /// with a remote called `remote_name`, for `LedgerSet`.
+    pub(crate) fn in_set(
+        repo: Repository,
+        local_path: PathBuf,
+        remote_name: &str,
+        branch_name: String,
+    ) -> GitLedger {
+        let tracking_ref = format!("remotes/{}/{}", remote_name, &branch_name);
+        GitLedger::without_sweep(
+            repo,
+            local_path,
+            branch_name,
+            Upstream::Remote {
+                name: remote_name.to_string(),
+                tracking_ref,
+            },
+        )
+    }
+
+    /// A ledger kept on `branch_name` in `backend` rather than a git remote,
+    /// with the repository at `local_path` as its local cache, creating a bare
+    /// repository if there is none.
+    ///
+    /// The cache is on disk even with a `MemoryBackend`: gix has no object
+    /// database that lives only in memory, and the ledger hands a
+    /// `Repository` to update functions to read and write objects with.
+    /// Transactions and ledger sets push through a git remote, so they
+    /// reject ledgers on a backend.
+    pub fn on_backend(
+        local_path: PathBuf,
+        backend: Arc<dyn Backend>,
+        branch_name: String,
+    ) -> LedgerResult<GitLedger> {
+        let repo = open_or_init(&local_path)?;
+        let tracking_ref = format!("refs/remotes/backend/{}", &branch_name);
+        Ok(GitLedger::with_upstream(
+            repo,
+            local_path,
+            branch_name,
+            Upstream::Backend {
+                backend,
+                tracking_ref,
+            },
+        ))
+    }
+
+    fn with_upstream(
+        repo: Repository,
+        local_path: PathBuf,
+        branch_name: String,
+        upstream: Upstream,
+    ) -> GitLedger {
+        // Clear out refs left behind by writers that crashed mid-push.
+        if let Err(e) = sweep_tmp_refs(&repo) {
+            log::warn!("sweeping stale tmp refs: {:#}", e);
+        }
+        GitLedger::without_sweep(repo, local_path, branch_name, upstream)
+    }
+
+    fn without_sweep(
+        mut repo: Repository,
+        local_path: PathBuf,
+        branch_name: String,
+        upstream: Upstream,
+    ) -> GitLedger {
         repo.object_cache_size_if_unset(4 * 1024 * 1024);
-        let tmp_ref = format!("refs/tmp/tmp{}", rand::thread_rng().gen::<u64>());
         let branch_ref = format!("refs/heads/{}", &branch_name);
-        let tracking_ref = format!("remotes/{}/{}", &remote_name, &branch_name);
-        Ok(GitLedger {
+        GitLedger {
             repo,
             local_path,
-            remote_name,
             branch_ref,
-            tracking_ref,
-            tmp_ref,
-        })
+            upstream,
+            commit_info: CommitInfo::default(),
+            retry_policy: RetryPolicy::default(),
+            allowed_signers: None,
+            divergence_policy: DivergencePolicy::default(),
+            fetch_depth: None,
+            synced: Arc::new(Mutex::new(None)),
+            observers: Observers::default(),
+        }
+    }
+
+    /// Whether this ledger was created with `local` and has no upstream.
+    pub fn is_local(&self) -> bool {
+        matches!(self.upstream, Upstream::Local)
+    }
+
+    /// Describe commits written by this ledger with `info` unless a call
+    /// supplies its own.
+    pub fn with_commit_info(mut self, info: CommitInfo) -> GitLedger {
+        self.commit_info = info;
+        self
+    }
+
+    pub fn commit_info(&self) -> &CommitInfo {
+        &self.commit_info
+    }
+
+    /// Retry lost races in `update_with` according to `policy`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> GitLedger {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    /// Refuse, with `UnverifiedCommit`, to fetch any commit not signed by one
+    /// of `allowed`. Commits already on the local branch are trusted, so
+    /// start from an empty local repository to check the whole history.
+    /// Local ledgers fetch nothing and so check nothing.
+    pub fn with_allowed_signers(mut self, allowed: AllowedSigners) -> GitLedger {
+        self.allowed_signers = Some(allowed);
+        self
+    }
+
+    pub fn allowed_signers(&self) -> Option<&AllowedSigners> {
+        self.allowed_signers.as_ref()
+    }
+
+    /// Handle upstream history rewrites according to `policy`.
+    pub fn with_divergence_policy(mut self, policy: DivergencePolicy) -> GitLedger {
+        self.divergence_policy = policy;
+        self
+    }
+
+    pub fn divergence_policy(&self) -> DivergencePolicy {
+        self.divergence_policy
+    }
+
+    /// Report fetches, pushes and lost races to `observer`, after any
+    /// observers registered earlier.
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> GitLedger {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Fetch only the newest `depth` commits of the upstream branch, leaving a
+    /// shallow local repository. History further back is fetched only when
+    /// needed to connect a new upstream tip to the local one, and `history`
+    /// stops where the local repository does. Replicated ledgers always fetch
+    /// full history.
+    pub fn with_fetch_depth(mut self, depth: NonZeroU32) -> GitLedger {
+        self.fetch_depth = Some(depth);
+        self
     }
 
-    pub fn update_once_with<'r, F, E>(&self, f: F) -> Result<Option<()>>
+    pub fn fetch_depth(&self) -> Option<NonZeroU32> {
+        self.fetch_depth
+    }
+
+    /// Fetch and move the local branch to the upstream one even if that
+    /// discards local commits, as `DivergencePolicy::AdoptRemote` would.
+    pub fn reset_to_remote(&self) -> LedgerResult<()> {
+        self.fetch_refs_with(DivergencePolicy::AdoptRemote)
+    }
+
+    /// Delete temporary refs left in the local repository by writers that
+    /// exited partway through a push, returning how many were deleted. This
+    /// also runs whenever a ledger is constructed.
+    ///
+    /// Only refs written on this host are swept, and hosts are told apart by
+    /// hostname: processes in separate PID namespaces sharing a hostname, such
+    /// as containers over one volume, must not share a local repository.
+    pub fn sweep_tmp_refs(&self) -> LedgerResult<usize> {
+        Ok(sweep_tmp_refs(&self.repo)?)
+    }
+
+    /// Sweep stale temporary refs, then delete loose objects in the local
+    /// repository that no ref reaches and that are over an hour old, returning
+    /// how many objects were deleted.
+    pub fn gc(&self) -> LedgerResult<usize> {
+        self.sweep_tmp_refs()?;
+        Ok(prune(&self.repo, PRUNE_GRACE)?)
+    }
+
+    /// The directory holding the local bare repository.
+    pub fn local_path(&self) -> &Path {
+        &self.local_path
+    }
+
+    pub fn update_once_with<F, E>(&self, f: F) -> LedgerResult<PushOutcome>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnOnce(
+            &Repository,
+            Option<(Commit<'_>, gix::Tree<'_>)>,
+        ) -> std::result::Result<TreeBuilder, E>,
+    {
+        self.update_once_with_info(&self.commit_info, f)
+    }
+
+    /// As `update_once_with`, describing the commit with `info`.
+    pub fn update_once_with_info<F, E>(&self, info: &CommitInfo, f: F) -> LedgerResult<PushOutcome>
     where
         E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
         F: FnOnce(
@@ -58,15 +408,27 @@ impl GitLedger {
         ) -> std::result::Result<TreeBuilder, E>,
     {
         let old = self.fetch()?;
-        let root_commit = match old.as_ref() {
-            Some((root_commit, _)) => Some(root_commit.id()),
-            None => None,
-        };
-        let tree = f(&self.repo, old).map_err(Into::into)?;
-        Ok(self.push(root_commit.map(Into::into), &tree)?.map(|_| ()))
+        let root_commit = old.as_ref().map(|(root_commit, _)| root_commit.id);
+        let tree = f(&self.repo, old).map_err(|e| LedgerError::Update(e.into()))?;
+        self.push_with_info(root_commit, &tree, info)
+    }
+
+    /// Repeatedly apply `f` to the latest upstream state until a push
+    /// succeeds, returning the new commit. Lost races are retried according to
+    /// the ledger's `RetryPolicy`.
+    pub fn update_with<F, E>(&self, f: F) -> LedgerResult<ObjectId>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnMut(
+            &Repository,
+            Option<(Commit<'_>, gix::Tree<'_>)>,
+        ) -> std::result::Result<TreeBuilder, E>,
+    {
+        self.update_with_info(&self.commit_info, f)
     }
 
-    pub fn update_with<'r, F, E>(&self, mut f: F) -> Result<()>
+    /// As `update_with`, describing the commit with `info`.
+    pub fn update_with_info<F, E>(&self, info: &CommitInfo, mut f: F) -> LedgerResult<ObjectId>
     where
         E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
         F: FnMut(
@@ -74,28 +436,185 @@ impl GitLedger {
             Option<(Commit<'_>, gix::Tree<'_>)>,
         ) -> std::result::Result<TreeBuilder, E>,
     {
+        let mut retry = self.retry_policy.start();
         loop {
-            if let Some(tb) = self.update_once_with(&mut f)? {
-                return Ok(tb);
+            if let PushOutcome::Committed(id) = self.update_once_with_info(info, &mut f)? {
+                return Ok(id);
             }
+            std::thread::sleep(retry.failed()?);
         }
     }
 
-    pub fn fetch(&self) -> Result<Option<(Commit<'_>, gix::Tree<'_>)>> {
+    /// As `update_with`, but on losing a race call `merge` with the state `f`
+    /// started from, the state it pushed and the state that won, and push
+    /// the merged tree over the winner instead of running `f` again. The
+    /// starting state is `None` if the ledger was empty.
+    pub fn update_with_merge<F, M, E>(&self, f: F, merge: M) -> LedgerResult<ObjectId>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnOnce(
+            &Repository,
+            Option<(Commit<'_>, gix::Tree<'_>)>,
+        ) -> std::result::Result<TreeBuilder, E>,
+        M: FnMut(
+            &Repository,
+            Option<gix::Tree<'_>>,
+            gix::Tree<'_>,
+            gix::Tree<'_>,
+        ) -> std::result::Result<TreeBuilder, E>,
+    {
+        self.update_with_merge_info(&self.commit_info, f, merge)
+    }
+
+    /// As `update_with_merge`, describing the commit with `info`.
+    pub fn update_with_merge_info<F, M, E>(
+        &self,
+        info: &CommitInfo,
+        f: F,
+        mut merge: M,
+    ) -> LedgerResult<ObjectId>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnOnce(
+            &Repository,
+            Option<(Commit<'_>, gix::Tree<'_>)>,
+        ) -> std::result::Result<TreeBuilder, E>,
+        M: FnMut(
+            &Repository,
+            Option<gix::Tree<'_>>,
+            gix::Tree<'_>,
+            gix::Tree<'_>,
+        ) -> std::result::Result<TreeBuilder, E>,
+    {
+        let mut retry = self.retry_policy.start();
+        let old = self.fetch()?;
+        let mut base = old.as_ref().map(|(commit, tree)| (commit.id, tree.id));
+        let mut ours = f(&self.repo, old).map_err(|e| LedgerError::Update(e.into()))?;
+        loop {
+            let parent = base.map(|(commit, _)| commit);
+            if let PushOutcome::Committed(id) = self.push_with_info(parent, &ours, info)? {
+                return Ok(id);
+            }
+            std::thread::sleep(retry.failed()?);
+
+            let (theirs, theirs_tree) = match self.fetch()? {
+                Some(theirs) => theirs,
+                // Upstream was emptied, so there is nothing to merge with.
+                None => {
+                    base = None;
+                    continue;
+                }
+            };
+            let base_tree = match base {
+                Some((_, tree)) => Some(self.find_tree(tree)?),
+                None => None,
+            };
+            let ours_tree = self.repo.write_object(&ours).context("write tree to git")?;
+            let ours_tree = self.find_tree(ours_tree.detach())?;
+            let next = (theirs.id, theirs_tree.id);
+            ours = merge(&self.repo, base_tree, ours_tree, theirs_tree)
+                .map_err(|e| LedgerError::Update(e.into()))?;
+            base = Some(next);
+        }
+    }
+
+    fn find_tree(&self, id: ObjectId) -> LedgerResult<gix::Tree<'_>> {
+        Ok(self
+            .repo
+            .find_object(id)
+            .context("find tree")?
+            .try_into_tree()
+            .context("expected tree")?)
+    }
+
+    pub fn fetch(&self) -> LedgerResult<Option<(Commit<'_>, gix::Tree<'_>)>> {
         self.fetch_refs()?;
+        self.read_branch()
+    }
+
+    /// As `fetch`, but skip contacting upstream when the last fetch is recent
+    /// enough for `freshness`.
+    pub fn fetch_with(&self, freshness: Freshness) -> LedgerResult<Snapshot<'_>> {
+        if self.needs_fetch(freshness) {
+            self.fetch_refs()?;
+        }
+        self.snapshot()
+    }
 
-        let reference = match self.repo.try_find_reference(&self.branch_ref)? {
+    pub(crate) fn needs_fetch(&self, freshness: Freshness) -> bool {
+        match freshness {
+            Freshness::Latest => true,
+            Freshness::MaxAge(max_age) => self.sync_age().is_none_or(|age| age > max_age),
+            Freshness::LocalOnly => false,
+        }
+    }
+
+    /// The local branch, with how long ago it was fetched.
+    pub(crate) fn snapshot(&self) -> LedgerResult<Snapshot<'_>> {
+        let age = self.sync_age();
+        Ok(Snapshot {
+            state: self.read_branch()?,
+            age,
+        })
+    }
+
+    fn sync_age(&self) -> Option<Duration> {
+        match self.upstream {
+            // The local branch is the upstream.
+            Upstream::Local => Some(Duration::ZERO),
+            _ => self.synced.lock().unwrap().map(|synced| synced.elapsed()),
+        }
+    }
+
+    /// Past states of the ledger, newest first, starting from the local branch
+    /// as of the last fetch.
+    pub fn history(&self, options: HistoryOptions) -> LedgerResult<History<'_>> {
+        let tip = peeled_only(
+            self.repo
+                .refs
+                .try_find(&self.branch_ref)
+                .context("find branch")?,
+        )?;
+        History::new(&self.repo, tip, options)
+    }
+
+    /// Poll upstream every `interval` on a background thread, reporting each
+    /// state committed after the last fetch.
+    pub fn watch(&self, interval: Duration) -> LedgerResult<Watch> {
+        let from = peeled_only(
+            self.repo
+                .refs
+                .try_find(&self.branch_ref)
+                .context("find branch")?,
+        )?;
+        Ok(Watch::new(self.clone(), interval, from))
+    }
+
+    /// Read the state of the local branch as of the last fetch.
+    pub(crate) fn read_branch(&self) -> LedgerResult<Option<(Commit<'_>, gix::Tree<'_>)>> {
+        let reference = match self
+            .repo
+            .try_find_reference(&self.branch_ref)
+            .context("find branch")?
+        {
             Some(r) => r,
             None => return Ok(None),
         };
-        let root_id = reference.clone().into_fully_peeled_id()?;
+        let root_id = reference.into_fully_peeled_id().context("peel branch")?;
 
-        let root_commit = self.repo.try_find_object(root_id)?.context("root commit")?;
+        let root_commit = self
+            .repo
+            .try_find_object(root_id)
+            .context("find root commit")?
+            .ok_or_else(|| LedgerError::CorruptLedger(format!("missing commit {}", root_id)))?;
         if root_commit.kind != Kind::Commit {
-            anyhow::bail!("Expected commit");
+            return Err(LedgerError::CorruptLedger(format!(
+                "expected commit at {}, found {}",
+                root_id, root_commit.kind
+            )));
         }
         let root_commit = root_commit.into_commit();
-        let root_tree = root_commit.tree()?;
+        let root_tree = root_commit.tree().context("root tree")?;
 
         Ok(Some((root_commit, root_tree)))
     }
@@ -104,108 +623,491 @@ impl GitLedger {
         &self,
         old_commit_id: Option<ObjectId>,
         tree: &TreeBuilder,
-    ) -> Result<Option<ObjectId>> {
-        let tree = self.repo.write_object(&tree).context("write tree to git")?;
+    ) -> LedgerResult<PushOutcome> {
+        self.push_with_info(old_commit_id, tree, &self.commit_info)
+    }
 
-        // FIXME: There is a brief race window here that would see tmp not cleaned
-        // up.
-        let new_commit_id: ObjectId = self
-            .repo
-            .commit(
-                self.tmp_ref.as_str(),
-                "A Commit In Time",
-                tree,
-                old_commit_id.into_iter(),
-            )
-            .context("commit to git")?
-            .into();
-
-        let result = match git_command()
-            .current_dir(&self.local_path)
-            .arg("push")
-            .arg(&self.remote_name)
-            .arg(format!("{}:{}", &self.tmp_ref, self.branch_ref))
-            .status()
-        {
-            Ok(status) if status.success() => Ok(Some(new_commit_id)),
-            Ok(..) => match self.maybe_raced(old_commit_id) {
-                Ok(true) => Ok(None),
-                Ok(false) => anyhow::bail!("a git command failed"),
-                Err(e) => Err(e).context("maybe raced"),
+    /// As `push`, describing the commit with `info`.
+    pub fn push_with_info(
+        &self,
+        old_commit_id: Option<ObjectId>,
+        tree: &TreeBuilder,
+        info: &CommitInfo,
+    ) -> LedgerResult<PushOutcome> {
+        let started = Instant::now();
+        self.observers.push_started();
+        let result = self.push_once(old_commit_id, tree, info);
+        self.observers
+            .push_finished(started.elapsed(), result.as_ref());
+        if let Ok(PushOutcome::RaceLost { expected, remote }) = result {
+            self.observers.race_lost(expected, remote);
+        }
+        result
+    }
+
+    fn push_once(
+        &self,
+        old_commit_id: Option<ObjectId>,
+        tree: &TreeBuilder,
+        info: &CommitInfo,
+    ) -> LedgerResult<PushOutcome> {
+        let pending = self.write_pending(old_commit_id, tree, info)?;
+        let new_commit_id = pending.id;
+
+        let pushed = self.push_upstream(new_commit_id, old_commit_id, info);
+        let result = match pushed {
+            Ok(RefPush::Updated) => Ok(PushOutcome::Committed(new_commit_id)),
+            Ok(RefPush::Stale) => match self.maybe_raced(old_commit_id) {
+                Ok(Some(outcome)) => Ok(outcome),
+                // Writers split the replicas between them, and none won.
+                Ok(None) if matches!(self.upstream, Upstream::Quorum(..)) => {
+                    Ok(PushOutcome::RaceLost {
+                        expected: old_commit_id,
+                        remote: old_commit_id,
+                    })
+                }
+                Ok(None) => Err(LedgerError::RemoteRejected {
+                    message: "remote branch moved during push".to_string(),
+                }),
+                Err(e) => Err(e),
             },
-            Err(e) => Err(e).context("subprocess failed"),
+            Ok(RefPush::Rejected(message)) => match self.maybe_raced(old_commit_id) {
+                Ok(Some(outcome)) => Ok(outcome),
+                Ok(None) => Err(LedgerError::RemoteRejected { message }),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
         };
 
+        drop(pending);
+        result
+    }
+
+    /// Move the upstream branch from `old_commit_id` to `new_commit_id`.
+    fn push_upstream(
+        &self,
+        new_commit_id: ObjectId,
+        old_commit_id: Option<ObjectId>,
+        info: &CommitInfo,
+    ) -> LedgerResult<RefPush> {
+        match &self.upstream {
+            Upstream::Remote { name, .. } => push_commit(
+                &self.repo,
+                name,
+                new_commit_id,
+                &self.branch_ref,
+                old_commit_id,
+                info,
+            ),
+            Upstream::Quorum(replicas) => push_replicas(
+                &self.repo,
+                replicas,
+                new_commit_id,
+                &self.branch_ref,
+                old_commit_id,
+                info,
+            ),
+            // Ledgers sharing the repository move the branch too, and would
+            // otherwise fail on its ref lock rather than wait.
+            Upstream::Local => {
+                let _lock = lock_refs(&self.repo)?;
+                update_ref(
+                    &self.repo,
+                    Some(new_commit_id),
+                    &self.branch_ref,
+                    old_commit_id,
+                    info,
+                )
+            }
+            Upstream::Backend { backend, .. } => {
+                backend.push(&self.repo, &self.branch_ref, new_commit_id, old_commit_id)
+            }
+        }
+    }
+
+    /// Write a commit of `tree` on top of `old_commit_id` to push.
+    pub(crate) fn write_pending(
+        &self,
+        old_commit_id: Option<ObjectId>,
+        tree: &TreeBuilder,
+        info: &CommitInfo,
+    ) -> LedgerResult<Pending<'_>> {
+        let tree = self.repo.write_object(tree).context("write tree to git")?;
+
+        // A crash before the tmp ref is deleted leaves it behind until
+        // `sweep_tmp_refs` notices this process is gone.
+        let tmp_ref = tmp_ref_name();
+        let id = write_commit(&self.repo, &tmp_ref, tree.detach(), old_commit_id, info)?;
+        Ok(Pending {
+            repo: &self.repo,
+            tmp_ref,
+            id,
+        })
+    }
+
+    pub(crate) fn branch_ref(&self) -> &str {
+        &self.branch_ref
+    }
+
+    /// The remote this ledger pushes to, `Ok(None)` if it has no upstream,
+    /// or an error naming `operation` if it is replicated or on a backend.
+    pub(crate) fn single_remote(&self, operation: &str) -> LedgerResult<Option<&str>> {
+        match &self.upstream {
+            Upstream::Remote { name, .. } => Ok(Some(name)),
+            Upstream::Local => Ok(None),
+            Upstream::Quorum(..) => Err(LedgerError::Repository(anyhow::anyhow!(
+                "{} is not supported for replicated ledgers",
+                operation
+            ))),
+            Upstream::Backend { .. } => Err(LedgerError::Repository(anyhow::anyhow!(
+                "{} is not supported for ledgers on a backend",
+                operation
+            ))),
+        }
+    }
+
+    pub(crate) fn fetch_refs(&self) -> LedgerResult<()> {
+        self.fetch_refs_with(self.divergence_policy)
+    }
+
+    fn fetch_refs_with(&self, policy: DivergencePolicy) -> LedgerResult<()> {
+        let started = Instant::now();
+        self.observers.fetch_started();
+        let result = self.sync_branch(policy);
+        self.observers
+            .fetch_finished(started.elapsed(), result.as_ref().copied());
+        result?;
+        self.mark_synced(started);
+        Ok(())
+    }
+
+    /// Record that a fetch started at `started` brought the local branch up
+    /// to date.
+    pub(crate) fn mark_synced(&self, started: Instant) {
+        *self.synced.lock().unwrap() = Some(started);
+    }
+
+    /// Bring the local branch up to date with upstream.
+    fn sync_branch(&self, policy: DivergencePolicy) -> LedgerResult<()> {
+        if self.is_local() {
+            return Ok(());
+        }
+        // Other processes, and ledgers in this one that opened the repository
+        // separately, fetch into and move the same refs.
+        let _lock = lock_refs(&self.repo)?;
+        let tracking_ref = match &self.upstream {
+            Upstream::Remote { tracking_ref, .. } | Upstream::Backend { tracking_ref, .. } => {
+                self.fetch_tracking()?;
+                tracking_ref
+            }
+            Upstream::Quorum(replicas) => {
+                return match fetch_replicas(
+                    &self.repo,
+                    replicas,
+                    &self.branch_ref,
+                    &self.commit_info,
+                    |tip| self.verify_new(tip),
+                ) {
+                    Err(LedgerError::TrackingDiverged { local, remote })
+                        if policy == DivergencePolicy::AdoptRemote =>
+                    {
+                        self.adopt_remote(local, remote)
+                    }
+                    result => result,
+                }
+            }
+            Upstream::Local => return Ok(()),
+        };
+        self.sync_tracking(tracking_ref, policy)
+    }
+
+    /// Fetch the upstream branch into the tracking branch.
+    fn fetch_tracking(&self) -> LedgerResult<()> {
+        match &self.upstream {
+            Upstream::Remote { name, .. } => {
+                fetch_remote(&self.repo, name, &self.branch_ref, self.shallow())?;
+            }
+            Upstream::Backend {
+                backend,
+                tracking_ref,
+            } => {
+                if let Some(tip) = backend.fetch(&self.repo, &self.branch_ref)? {
+                    self.repo
+                        .reference(tracking_ref.as_str(), tip, PreviousValue::Any, "fetch")
+                        .context("update tracking branch")?;
+                }
+            }
+            Upstream::Quorum(..) | Upstream::Local => {}
+        }
+        Ok(())
+    }
+
+    /// Bring the local branch up to date with its tracking branch, which was
+    /// just fetched as part of a batch under the refs lock.
+    pub(crate) fn sync_fetched(&self) -> LedgerResult<()> {
+        match &self.upstream {
+            Upstream::Remote { tracking_ref, .. } | Upstream::Backend { tracking_ref, .. } => {
+                self.sync_tracking(tracking_ref, self.divergence_policy)
+            }
+            Upstream::Quorum(..) | Upstream::Local => Ok(()),
+        }
+    }
+
+    fn sync_tracking(&self, tracking_ref: &str, policy: DivergencePolicy) -> LedgerResult<()> {
+        if let Some(tip) = peeled_only(self.repo.refs.try_find(tracking_ref).context("find")?)? {
+            self.deepen_to_local(tip)?;
+            self.verify_new(tip)?;
+        }
+
+        if !fast_forward_reference(&self.repo, &self.branch_ref, tracking_ref)? {
+            let local = peeled_only(self.repo.refs.try_find(&self.branch_ref).context("find")?)?;
+            let remote = peeled_only(self.repo.refs.try_find(tracking_ref).context("find")?)?;
+            let local = local.context("local branch vanished")?;
+            let remote = remote.context("tracking branch vanished")?;
+            log::trace!("fetch_refs: local {} diverged from {}", local, remote);
+            return match policy {
+                DivergencePolicy::Refuse => Err(LedgerError::TrackingDiverged { local, remote }),
+                DivergencePolicy::AdoptRemote => self.adopt_remote(local, remote),
+                DivergencePolicy::RepushLocal => self.repush_local(local, remote),
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Point the local branch at `remote`, backing up `local`.
+    fn adopt_remote(&self, local: ObjectId, remote: ObjectId) -> LedgerResult<()> {
+        self.backup(local)?;
         self.repo
-            .find_reference(self.tmp_ref.as_str())
-            .context("find_reference")?
-            .delete()
-            .context("delete")?;
+            .reference(
+                self.branch_ref.as_str(),
+                remote,
+                PreviousValue::MustExistAndMatch(Target::Peeled(local)),
+                "adopt rewritten upstream",
+            )
+            .context("reset branch")?;
+        Ok(())
+    }
 
-        result
+    /// Force `local` back over `remote` upstream, backing up `remote`.
+    fn repush_local(&self, local: ObjectId, remote: ObjectId) -> LedgerResult<()> {
+        self.backup(remote)?;
+        match self.push_upstream(local, Some(remote), &self.commit_info)? {
+            RefPush::Updated => self.fetch_tracking(),
+            RefPush::Stale => Err(LedgerError::TrackingDiverged { local, remote }),
+            RefPush::Rejected(message) => Err(LedgerError::RemoteRejected { message }),
+        }
     }
 
-    fn fetch_refs(&self) -> Result<()> {
-        let interrupted = core::sync::atomic::AtomicBool::new(false);
-        let remote = self.repo.find_remote(self.remote_name.as_str())?;
-        let remote = remote.connect(Direction::Fetch)?;
-        let fetch =
-            remote.prepare_fetch(DiscardProgress, gix::remote::ref_map::Options::default())?;
-        fetch.receive(DiscardProgress, &interrupted)?;
-        if interrupted.load(core::sync::atomic::Ordering::SeqCst) {
-            anyhow::bail!("Interrupted.");
+    fn shallow(&self) -> Shallow {
+        match self.fetch_depth {
+            Some(depth) => Shallow::DepthAtRemote(depth),
+            None => Shallow::NoChange,
         }
+    }
 
-        if !fast_forward_reference(&self.repo, &self.branch_ref, &self.tracking_ref)? {
-            anyhow::bail!("Tracking branch cannot fast forward.");
+    /// A shallow fetch may stop short of the local tip, hiding that `tip`
+    /// descends from it. Deepen, doubling the depth each time, until it
+    /// shows or no history is left to fetch.
+    fn deepen_to_local(&self, tip: ObjectId) -> LedgerResult<()> {
+        let (
+            Some(depth),
+            Upstream::Remote {
+                name: remote_name, ..
+            },
+        ) = (self.fetch_depth, &self.upstream)
+        else {
+            return Ok(());
+        };
+        let local = match peeled_only(self.repo.refs.try_find(&self.branch_ref).context("find")?)? {
+            Some(local) => local,
+            None => return Ok(()),
+        };
+
+        let mut depth = depth.get();
+        while !is_ancestor(&self.repo, local, tip)? {
+            let Some(boundary) = self.repo.shallow_commits().context("read shallow")? else {
+                break;
+            };
+            log::trace!("deepen_to_local: deepening by {}", depth);
+            fetch_remote(
+                &self.repo,
+                remote_name,
+                &self.branch_ref,
+                Shallow::Deepen(depth),
+            )?;
+            let deepened = self.repo.shallow_commits().context("read shallow")?;
+            if deepened.is_some_and(|deepened| deepened.iter().eq(boundary.iter())) {
+                break;
+            }
+            depth = depth.saturating_mul(2);
         }
+        Ok(())
+    }
 
+    fn backup(&self, id: ObjectId) -> LedgerResult<()> {
+        let branch = self
+            .branch_ref
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&self.branch_ref);
+        self.repo
+            .reference(
+                format!("refs/ledger-backup/{}/{}", branch, id),
+                id,
+                PreviousValue::Any,
+                "back up diverged tip",
+            )
+            .context("write backup ref")?;
         Ok(())
     }
 
-    fn maybe_raced(&self, old_commit_id: Option<ObjectId>) -> Result<bool> {
+    /// Check signatures on the commits that fast forwarding the local branch
+    /// to `tip` would bring in.
+    fn verify_new(&self, tip: ObjectId) -> LedgerResult<()> {
+        let allowed = match &self.allowed_signers {
+            Some(allowed) => allowed,
+            None => return Ok(()),
+        };
+        let local = peeled_only(self.repo.refs.try_find(&self.branch_ref).context("find")?)?;
+        if local == Some(tip) {
+            return Ok(());
+        }
+        verify_commits(&self.repo, allowed, tip, local)
+    }
+
+    /// The upstream branch as of the last fetch.
+    pub(crate) fn upstream_tip(&self) -> LedgerResult<Option<ObjectId>> {
+        let upstream_ref = match &self.upstream {
+            Upstream::Remote { tracking_ref, .. } | Upstream::Backend { tracking_ref, .. } => {
+                tracking_ref
+            }
+            Upstream::Quorum(..) | Upstream::Local => &self.branch_ref,
+        };
+        Ok(peeled_only(
+            self.repo.refs.try_find(upstream_ref).context("find")?,
+        )?)
+    }
+
+    /// Called after a failed push to find out whether it was because someone
+    /// else advanced the upstream branch first.
+    pub(crate) fn maybe_raced(
+        &self,
+        old_commit_id: Option<ObjectId>,
+    ) -> LedgerResult<Option<PushOutcome>> {
         self.fetch_refs()?;
-        let remote_id = peeled_only(self.repo.refs.try_find(&self.tracking_ref)?)?;
+        let remote_id = self.upstream_tip()?;
 
         if old_commit_id != remote_id {
             log::trace!("maybe_raced: {:?} != {:?}", &old_commit_id, &remote_id);
-            // TODO: Structured errors for this crate. In particular, the option
-            // returns are dangerous because there's no warning if they are
-            // ignored.
-            return Ok(true);
+            return Ok(Some(PushOutcome::RaceLost {
+                expected: old_commit_id,
+                remote: remote_id,
+            }));
         }
 
-        Ok(false)
+        Ok(None)
     }
 }
 
+/// Fetch `branch_ref` alone from `remote_name` into its tracking branch,
+/// returning the commit it pointed at, if any. Other branches and tags are
+/// neither fetched nor, over protocol v2, listed by the remote beyond those
+/// whose names start with the branch's.
+pub(crate) fn fetch_remote(
+    repo: &Repository,
+    remote_name: &str,
+    branch_ref: &str,
+    shallow: Shallow,
+) -> LedgerResult<Option<ObjectId>> {
+    Ok(fetch_branches(repo, remote_name, &[branch_ref], shallow)?[0])
+}
+
+/// As `fetch_remote` for every branch in `branch_refs` in one fetch,
+/// returning their commits in order.
+pub(crate) fn fetch_branches(
+    repo: &Repository,
+    remote_name: &str,
+    branch_refs: &[&str],
+    shallow: Shallow,
+) -> LedgerResult<Vec<Option<ObjectId>>> {
+    let interrupted = core::sync::atomic::AtomicBool::new(false);
+    let remote = narrow_remote(repo, remote_name, branch_refs)?;
+    let remote = remote
+        .connect(Direction::Fetch)
+        .map_err(|e| LedgerError::RemoteUnreachable(e.into()))?;
+    let fetch = remote
+        .prepare_fetch(DiscardProgress, gix::remote::ref_map::Options::default())
+        .map_err(|e| LedgerError::RemoteUnreachable(e.into()))?
+        .with_shallow(shallow);
+    let tips = branch_refs
+        .iter()
+        .map(|branch_ref| {
+            fetch
+                .ref_map()
+                .remote_refs
+                .iter()
+                .map(|r| r.unpack())
+                .find(|(name, ..)| name == branch_ref)
+                .and_then(|(_, target, peeled)| peeled.or(target))
+                .map(ToOwned::to_owned)
+        })
+        .collect();
+    fetch
+        .receive(DiscardProgress, &interrupted)
+        .map_err(|e| LedgerError::RemoteUnreachable(e.into()))?;
+    if interrupted.load(core::sync::atomic::Ordering::SeqCst) {
+        return Err(LedgerError::RemoteUnreachable(anyhow::anyhow!(
+            "Interrupted."
+        )));
+    }
+    Ok(tips)
+}
+
+/// `remote_name` with its fetch refspecs replaced by one per branch in
+/// `branch_refs`, each mapped onto its tracking branch, and tags off.
+///
+/// gix asks the remote to list only refs under the first two components of
+/// a full source name, `refs/heads/`, which would list every branch. A
+/// partial source, `heads/<branch>`, instead sends each name it could expand
+/// to, among them `refs/heads/<branch>`, so the listing is narrowed to the
+/// branch. Unlike the bare branch name, it cannot also match a tag of the
+/// same name.
+fn narrow_remote<'repo>(
+    repo: &'repo Repository,
+    remote_name: &str,
+    branch_refs: &[&str],
+) -> LedgerResult<gix::Remote<'repo>> {
+    let refspecs: Vec<_> = branch_refs
+        .iter()
+        .map(|branch_ref| {
+            let branch = branch_ref.strip_prefix("refs/heads/").unwrap_or(branch_ref);
+            format!("+heads/{}:refs/remotes/{}/{}", branch, remote_name, branch)
+        })
+        .collect();
+    let mut remote = repo
+        .find_remote(remote_name)
+        .context("find remote")?
+        .with_fetch_tags(Tags::None);
+    remote
+        .replace_refspecs(refspecs.iter().map(String::as_str), Direction::Fetch)
+        .context("narrow refspec")?;
+    Ok(remote)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use crate::Identity;
+
+    use anyhow::Result;
     use gix_object::tree::{Entry, EntryMode};
 
     macro_rules! init {
         ($n:expr, $path:expr) => {{
-            let upstream_path = $path.join("upstream");
-            if !upstream_path.exists() {
-                gix::init_bare(&upstream_path).unwrap();
-            }
-
-            let make_ledger = |j|
-            {
-                let local_path = $path.join(format!("local{}", j));
-                GitLedger::new(
-                    local_path.clone(),
-                    upstream_path.to_string_lossy().to_string(),
-                    "origin".to_string(),
-                    "main".to_string(),
-                )
-                    .unwrap()
-            };
-
             let mut j = 0;
-            let ledgers: [GitLedger; $n] = arr_macro::arr![make_ledger({j += 1; j}); $n];
+            let ledgers: [GitLedger; $n] =
+                arr_macro::arr![crate::testing::ledger($path, {j += 1; j}); $n];
             ledgers
         }};
 
@@ -286,13 +1188,20 @@ mod tests {
             filename: "double".into(),
         });
 
-        gledger1.push(None, &tb1).unwrap().unwrap();
-        assert!(gledger2.push(None, &tb2).unwrap().is_none());
+        gledger1.push(None, &tb1).unwrap().committed().unwrap();
+        assert_eq!(
+            gledger2.push(None, &tb2).unwrap(),
+            PushOutcome::RaceLost {
+                expected: None,
+                remote: gledger1.fetch().unwrap().map(|(commit, _)| commit.id)
+            }
+        );
 
         let (commit1, tree1) = gledger2.fetch().unwrap().unwrap();
         gledger2
-            .push(Some(commit1.id().into()), &tb2)
+            .push(Some(commit1.id), &tb2)
             .unwrap()
+            .committed()
             .unwrap();
 
         let (_commit2, tree2) = gledger1.fetch().unwrap().unwrap();
@@ -306,4 +1215,589 @@ mod tests {
             ObjectId::from(b)
         );
     }
+
+    #[test]
+    fn test_retries_exhausted() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let mut gledgers = init!(2, tmp.path()).into_iter();
+
+        let gledger1 = gledgers
+            .next()
+            .unwrap()
+            .with_retry_policy(RetryPolicy::immediate().with_max_attempts(3));
+        let gledger2 = gledgers.next().unwrap();
+
+        let mut calls = 0;
+        let result = gledger1.update_with(|_repo, _st| {
+            // Every attempt loses to a write by the other ledger.
+            calls += 1;
+            let old = gledger2.fetch()?.map(|(commit, _)| commit.id);
+            let blob = gledger2.repo.write_blob(calls.to_string())?;
+            let mut tb = TreeBuilder::empty();
+            tb.entries.push(Entry {
+                oid: blob.into(),
+                mode: EntryMode::Blob,
+                filename: "other".into(),
+            });
+            gledger2.push(old, &tb)?.into_result()?;
+            anyhow::Ok(TreeBuilder::empty())
+        });
+
+        assert!(matches!(
+            result,
+            Err(LedgerError::RetriesExhausted { attempts: 3, .. })
+        ));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_history() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let gledger = init!(tmp.path());
+        assert!(gledger
+            .history(HistoryOptions::default())
+            .unwrap()
+            .next()
+            .is_none());
+
+        let mut ids = Vec::new();
+        for i in 0..4 {
+            let info = gledger
+                .commit_info()
+                .clone()
+                .with_message(format!("state {}", i))
+                .with_time(gix::date::Time::new(1_600_000_000 + i, 0));
+            let blob = gledger.repo.write_blob(i.to_string()).unwrap();
+            let mut tb = TreeBuilder::empty();
+            tb.entries.push(Entry {
+                oid: blob.into(),
+                mode: EntryMode::Blob,
+                filename: "single".into(),
+            });
+            let id = gledger
+                .push_with_info(ids.last().copied(), &tb, &info)
+                .unwrap()
+                .committed()
+                .unwrap();
+            ids.push(id);
+        }
+        gledger.fetch().unwrap();
+
+        let mut history: Vec<_> = gledger
+            .history(HistoryOptions::default())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            history.iter().map(|entry| entry.id).collect::<Vec<_>>(),
+            ids.iter().rev().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(history[0].message, "state 3");
+        assert_eq!(history[3].time.seconds(), 1_600_000_000);
+        let blob = history
+            .remove(1)
+            .tree
+            .lookup_entry_by_path("single")
+            .unwrap()
+            .unwrap();
+        assert_eq!(&blob.object().unwrap().data[..], b"2");
+
+        let recent: Vec<_> = gledger
+            .history(HistoryOptions::default().with_stop_at_commit(ids[1]))
+            .unwrap()
+            .map(|entry| entry.unwrap().id)
+            .collect();
+        assert_eq!(recent, [ids[3], ids[2]]);
+
+        let recent: Vec<_> = gledger
+            .history(
+                HistoryOptions::default()
+                    .with_stop_at_time(gix::date::Time::new(1_600_000_001, 0))
+                    .with_first_parent_only(),
+            )
+            .unwrap()
+            .map(|entry| entry.unwrap().id)
+            .collect();
+        assert_eq!(recent, [ids[3], ids[2], ids[1]]);
+    }
+
+    #[test]
+    fn test_commit_info() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let time = gix::date::Time::new(1_600_000_000, 3600);
+        let gledger = init!(tmp.path()).with_commit_info(
+            CommitInfo::new(Identity::new("Ledger", "ledger@example.com"), "default")
+                .with_time(time),
+        );
+
+        let tb = TreeBuilder::empty();
+        let first = gledger.push(None, &tb).unwrap().committed().unwrap();
+        let info = CommitInfo::new(Identity::new("Auditor", "audit@example.com"), "audited")
+            .with_committer(Identity::new("Bot", "bot@example.com"));
+        let second = gledger
+            .push_with_info(Some(first), &tb, &info)
+            .unwrap()
+            .committed()
+            .unwrap();
+
+        let first = gledger.repo.find_object(first).unwrap().into_commit();
+        let first = first.decode().unwrap();
+        assert_eq!(first.message, "default");
+        assert_eq!(first.author.name, "Ledger");
+        assert_eq!(first.committer.email, "ledger@example.com");
+        assert_eq!(first.author.time, time);
+
+        let second = gledger.repo.find_object(second).unwrap().into_commit();
+        let second = second.decode().unwrap();
+        assert_eq!(second.message, "audited");
+        assert_eq!(second.author.name, "Auditor");
+        assert_eq!(second.committer.name, "Bot");
+    }
+
+    #[test]
+    fn test_local() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let path = tmp.path().join("ledger");
+        let gledger1 = GitLedger::local(path.clone(), "main".to_string()).unwrap();
+        let gledger2 = GitLedger::local(path.clone(), "main".to_string()).unwrap();
+        assert!(gledger1.is_local());
+        assert!(gledger1.repo.remote_names().is_empty());
+        assert!(gledger1.fetch().unwrap().is_none());
+
+        let first = gledger1
+            .push(None, &TreeBuilder::empty())
+            .unwrap()
+            .committed()
+            .unwrap();
+        let blob = gledger2.repo.write_blob(b"other").unwrap();
+        let mut tb = TreeBuilder::empty();
+        tb.entries.push(Entry {
+            oid: blob.into(),
+            mode: EntryMode::Blob,
+            filename: "other".into(),
+        });
+        assert_eq!(
+            gledger2.push(None, &tb).unwrap(),
+            PushOutcome::RaceLost {
+                expected: None,
+                remote: Some(first),
+            }
+        );
+
+        for ledger in [&gledger1, &gledger2] {
+            ledger
+                .update_with(|repo, st| {
+                    let count = match st {
+                        None => 0,
+                        Some((_commit, tree)) => tree.decode()?.entries.len(),
+                    };
+                    let blob = repo.write_blob(b"")?;
+                    let mut tb = TreeBuilder::empty();
+                    tb.entries.push(Entry {
+                        oid: blob.into(),
+                        mode: EntryMode::Blob,
+                        filename: format!("entry{}", count).into(),
+                    });
+                    anyhow::Ok(tb)
+                })
+                .unwrap();
+        }
+
+        let reopened = GitLedger::local(path, "main".to_string()).unwrap();
+        let (commit, _tree) = reopened.fetch().unwrap().unwrap();
+        assert_eq!(
+            gledger1.fetch().unwrap().map(|(commit, _)| commit.id),
+            Some(commit.id)
+        );
+        assert_eq!(
+            reopened.history(HistoryOptions::default()).unwrap().count(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_rewritten_upstream() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let mut gledgers = init!(2, tmp.path()).into_iter();
+        let gledger1 = gledgers.next().unwrap();
+        let gledger2 = gledgers
+            .next()
+            .unwrap()
+            .with_divergence_policy(DivergencePolicy::RepushLocal);
+
+        let local = gledger1
+            .push(None, &TreeBuilder::empty())
+            .unwrap()
+            .committed()
+            .unwrap();
+        gledger1.fetch().unwrap().unwrap();
+        gledger2.fetch().unwrap().unwrap();
+
+        // Force an unrelated history onto the upstream branch.
+        let upstream = gix::open(tmp.path().join("upstream")).unwrap();
+        let blob = upstream.write_blob(b"rewritten").unwrap();
+        let mut tb = TreeBuilder::empty();
+        tb.entries.push(Entry {
+            oid: blob.into(),
+            mode: EntryMode::Blob,
+            filename: "rewritten".into(),
+        });
+        let tree = upstream.write_object(&tb).unwrap().detach();
+        let remote = write_commit(
+            &upstream,
+            "refs/rewrite",
+            tree,
+            None,
+            &CommitInfo::default(),
+        )
+        .unwrap();
+        let rewrite = || {
+            upstream
+                .reference("refs/heads/main", remote, PreviousValue::Any, "rewrite")
+                .unwrap();
+        };
+        rewrite();
+        let backup = |ledger: &GitLedger, id: ObjectId| {
+            ledger
+                .repo
+                .try_find_reference(format!("refs/ledger-backup/main/{}", id).as_str())
+                .unwrap()
+                .is_some()
+        };
+
+        match gledger1.fetch() {
+            Err(LedgerError::TrackingDiverged {
+                local: l,
+                remote: r,
+            }) => {
+                assert_eq!((l, r), (local, remote))
+            }
+            other => panic!("expected divergence, got {:?}", other.map(|_| ())),
+        }
+
+        // Re-pushing restores the upstream branch to the local history.
+        let tip = gledger2.fetch().unwrap().map(|(commit, _)| commit.id);
+        assert_eq!(tip, Some(local));
+        assert!(backup(&gledger2, remote));
+        assert_eq!(
+            upstream.find_reference("refs/heads/main").unwrap().id(),
+            local
+        );
+        gledger1.fetch().unwrap();
+
+        rewrite();
+        gledger1.reset_to_remote().unwrap();
+        let (commit, _tree) = gledger1.fetch().unwrap().unwrap();
+        assert_eq!(commit.id, remote);
+        assert!(backup(&gledger1, local));
+
+        let gledger2 = gledger2.with_divergence_policy(DivergencePolicy::AdoptRemote);
+        let (commit, _tree) = gledger2.fetch().unwrap().unwrap();
+        assert_eq!(commit.id, remote);
+        assert!(backup(&gledger2, local));
+    }
+
+    #[test]
+    fn test_narrow_shallow_fetch() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let mut gledgers = init!(2, tmp.path()).into_iter();
+        let gledger1 = gledgers.next().unwrap();
+        let gledger2 = gledgers
+            .next()
+            .unwrap()
+            .with_fetch_depth(NonZeroU32::new(1).unwrap());
+
+        let bump = |ledger: &GitLedger| {
+            ledger
+                .update_with(|repo, st| {
+                    let count = match st {
+                        None => 0,
+                        Some((_commit, tree)) => tree.decode()?.entries.len(),
+                    };
+                    let mut tb = TreeBuilder::empty();
+                    for i in 0..=count {
+                        tb.entries.push(Entry {
+                            oid: repo.write_blob(i.to_string())?.into(),
+                            mode: EntryMode::Blob,
+                            filename: format!("entry{}", i).into(),
+                        });
+                    }
+                    anyhow::Ok(tb)
+                })
+                .unwrap()
+        };
+        for _ in 0..5 {
+            bump(&gledger1);
+        }
+
+        // Neither other branches nor tags are fetched.
+        let upstream = gix::open(tmp.path().join("upstream")).unwrap();
+        let tip = upstream.find_reference("refs/heads/main").unwrap().id();
+        for name in ["refs/heads/other", "refs/tags/v1"] {
+            upstream
+                .reference(name, tip, PreviousValue::Any, "test")
+                .unwrap();
+        }
+
+        let (commit, _tree) = gledger2.fetch().unwrap().unwrap();
+        assert_eq!(commit.id, tip);
+        let parent = commit.parent_ids().next().unwrap().detach();
+        drop(commit);
+        assert!(gledger2.repo.is_shallow());
+        assert!(gledger2.repo.find_object(parent).is_err());
+        for name in ["refs/remotes/origin/other", "refs/tags/v1"] {
+            assert!(gledger2.repo.try_find_reference(name).unwrap().is_none());
+        }
+
+        // Fetching past a gap deeper than the shallow history still fast
+        // forwards, and pushes from the shallow repository land.
+        for _ in 0..3 {
+            bump(&gledger1);
+        }
+        let tip = gledger1.fetch().unwrap().map(|(commit, _)| commit.id);
+        assert_eq!(gledger2.fetch().unwrap().map(|(commit, _)| commit.id), tip);
+        let tip = bump(&gledger2);
+        assert_eq!(
+            gledger1.fetch().unwrap().map(|(commit, _)| commit.id),
+            Some(tip)
+        );
+        gledger2.gc().unwrap();
+        assert_eq!(
+            gledger2.fetch().unwrap().map(|(commit, _)| commit.id),
+            Some(tip)
+        );
+    }
+
+    #[test]
+    fn test_narrow_ref_advertisement() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let gledger = init!(tmp.path());
+        let tip = gledger
+            .push(None, &TreeBuilder::empty())
+            .unwrap()
+            .committed()
+            .unwrap();
+        let upstream = gix::open(tmp.path().join("upstream")).unwrap();
+        for name in [
+            "refs/heads/other",
+            "refs/tags/main",
+            "refs/tags/v1",
+            "refs/notes/commits",
+        ] {
+            upstream
+                .reference(name, tip, PreviousValue::Any, "test")
+                .unwrap();
+        }
+
+        // Only main is listed, not its siblings, nor a tag of the same name.
+        let remote = narrow_remote(&gledger.repo, "origin", &["refs/heads/main"]).unwrap();
+        let ref_map = remote
+            .connect(Direction::Fetch)
+            .unwrap()
+            .ref_map(DiscardProgress, gix::remote::ref_map::Options::default())
+            .unwrap();
+        let mut advertised: Vec<_> = ref_map
+            .remote_refs
+            .iter()
+            .map(|r| r.unpack().0.to_string())
+            .collect();
+        advertised.sort();
+        assert_eq!(advertised, ["refs/heads/main"]);
+        assert_eq!(ref_map.mappings.len(), 1);
+        assert_eq!(gledger.fetch().unwrap().unwrap().0.id, tip);
+    }
+
+    #[test]
+    fn test_shared_local_path() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let upstream = crate::testing::upstream(tmp.path(), "upstream");
+
+        // Each thread opens the shared, not yet created, repository itself.
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let (path, upstream) = (tmp.path().join("shared"), upstream.clone());
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    let ledger =
+                        GitLedger::new(path, upstream, "origin".to_string(), "main".to_string())
+                            .unwrap()
+                            .with_retry_policy(RetryPolicy::default().with_max_attempts(100));
+                    ledger
+                        .update_with(|repo, st| {
+                            let mut tb = match st {
+                                None => TreeBuilder::empty(),
+                                Some((_commit, tree)) => TreeBuilder::from(tree.decode()?),
+                            };
+                            tb.entries.push(Entry {
+                                oid: repo.write_blob(b"")?.into(),
+                                mode: EntryMode::Blob,
+                                filename: format!("entry{}", i).into(),
+                            });
+                            tb.entries.sort();
+                            anyhow::Ok(tb)
+                        })
+                        .unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let reader = init!(tmp.path());
+        let (_commit, tree) = reader.fetch().unwrap().unwrap();
+        assert_eq!(tree.decode().unwrap().entries.len(), 8);
+    }
+
+    #[test]
+    fn test_concurrent_clones() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger = init!(tmp.path()).with_retry_policy(RetryPolicy::immediate());
+
+        // Clones share one repository handle, and push in parallel.
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let ledger = ledger.clone();
+                std::thread::spawn(move || {
+                    for j in 0..4 {
+                        ledger
+                            .update_with(|repo, st| {
+                                let mut tb = match st {
+                                    None => TreeBuilder::empty(),
+                                    Some((_commit, tree)) => TreeBuilder::from(tree.decode()?),
+                                };
+                                tb.entries.push(Entry {
+                                    oid: repo.write_blob(b"")?.into(),
+                                    mode: EntryMode::Blob,
+                                    filename: format!("entry{}-{}", i, j).into(),
+                                });
+                                tb.entries.sort();
+                                anyhow::Ok(tb)
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let (_commit, tree) = ledger.fetch().unwrap().unwrap();
+        assert_eq!(tree.decode().unwrap().entries.len(), 32);
+        let references = ledger.repo.references().unwrap();
+        assert_eq!(references.prefixed("refs/tmp/").unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_fetch_with() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let mut gledgers = init!(2, tmp.path()).into_iter();
+        let gledger1 = gledgers.next().unwrap();
+        let gledger2 = gledgers.next().unwrap();
+        let fetch_with = |freshness| {
+            let snapshot = gledger2.fetch_with(freshness).unwrap();
+            (snapshot.state.map(|(commit, _)| commit.id), snapshot.age)
+        };
+        let hour = Duration::from_secs(60 * 60);
+
+        let first = gledger1
+            .push(None, &TreeBuilder::empty())
+            .unwrap()
+            .committed()
+            .unwrap();
+        assert_eq!(fetch_with(Freshness::LocalOnly), (None, None));
+
+        // Never fetched, so the age is unknown, which forces a fetch.
+        let (state, age) = fetch_with(Freshness::MaxAge(hour));
+        assert_eq!(state, Some(first));
+        assert!(age.unwrap() < hour);
+
+        let blob = gledger1.repo.write_blob(b"second").unwrap();
+        let mut tb = TreeBuilder::empty();
+        tb.entries.push(Entry {
+            oid: blob.into(),
+            mode: EntryMode::Blob,
+            filename: "second".into(),
+        });
+        let second = gledger1
+            .push(Some(first), &tb)
+            .unwrap()
+            .committed()
+            .unwrap();
+
+        std::thread::sleep(Duration::from_millis(10));
+        let (state, age) = fetch_with(Freshness::MaxAge(hour));
+        assert_eq!(state, Some(first));
+        assert!(age.unwrap() >= Duration::from_millis(10));
+        assert_eq!(
+            fetch_with(Freshness::MaxAge(Duration::ZERO)).0,
+            Some(second)
+        );
+        assert_eq!(fetch_with(Freshness::LocalOnly).0, Some(second));
+
+        // Clones share when they last fetched.
+        let clone = gledger2.clone();
+        assert!(!clone.needs_fetch(Freshness::MaxAge(hour)));
+        assert!(clone.needs_fetch(Freshness::Latest));
+    }
+
+    #[test]
+    fn test_update_with_merge() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let mut gledgers = init!(2, tmp.path()).into_iter();
+        let gledger1 = gledgers.next().unwrap();
+        let gledger2 = gledgers.next().unwrap();
+
+        let add = |repo: &Repository, tree: Option<gix::Tree<'_>>, name: &str| {
+            let mut tb = match tree {
+                Some(tree) => TreeBuilder::from(tree.decode()?),
+                None => TreeBuilder::empty(),
+            };
+            tb.entries.push(Entry {
+                oid: repo.write_blob(name)?.into(),
+                mode: EntryMode::Blob,
+                filename: name.into(),
+            });
+            tb.entries.sort();
+            anyhow::Ok(tb)
+        };
+        let names = |tree: &gix::Tree<'_>| -> Vec<String> {
+            let tree = tree.decode().unwrap();
+            tree.entries
+                .iter()
+                .map(|e| e.filename.to_string())
+                .collect()
+        };
+        gledger1
+            .update_with(|repo, st| add(repo, st.map(|(_, tree)| tree), "a"))
+            .unwrap();
+
+        // Another writer lands while the first is computing its update.
+        let mut merges = 0;
+        gledger1
+            .update_with_merge(
+                |repo, st| {
+                    gledger2
+                        .update_with(|repo, st| add(repo, st.map(|(_, tree)| tree), "b"))
+                        .unwrap();
+                    add(repo, st.map(|(_, tree)| tree), "c")
+                },
+                |repo, base, ours, theirs| {
+                    merges += 1;
+                    assert_eq!(names(&base.unwrap()), ["a"]);
+                    assert_eq!(names(&ours), ["a", "c"]);
+                    assert_eq!(names(&theirs), ["a", "b"]);
+                    add(repo, Some(theirs), "c")
+                },
+            )
+            .unwrap();
+        assert_eq!(merges, 1);
+
+        let (commit, tree) = gledger2.fetch().unwrap().unwrap();
+        assert_eq!(names(&tree), ["a", "b", "c"]);
+        assert_eq!(commit.parent_ids().count(), 1);
+    }
 }
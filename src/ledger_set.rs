@@ -0,0 +1,396 @@
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::Context;
+use gix::progress::Discard as DiscardProgress;
+use gix::remote::fetch::Shallow;
+use gix::remote::Direction;
+use gix::Repository;
+use gix_hash::ObjectId;
+use gix_object::Tree as TreeBuilder;
+
+use crate::error::{LedgerError, LedgerResult, PushOutcome};
+use crate::gc::sweep_tmp_refs;
+use crate::ledger::fetch_branches;
+use crate::lock::lock_refs;
+use crate::observer::Observer;
+use crate::push::{push_each, RefPush, RefUpdate};
+use crate::util::init_repo;
+use crate::GitLedger;
+
+/// Many ledgers, each on its own branch under a common prefix, kept in one
+/// local repository and object store and synchronized with one remote.
+///
+/// `ledger` makes a `GitLedger` for a branch without opening another
+/// repository, so creating one is cheap, and the ledger is created upstream
+/// by its first push. Each works on its own as usual, and `fetch` and `push`
+/// serve several of them in a single round trip. `fetch` and `push` reject
+/// ledgers from outside the set, including replicated ledgers and ledgers on
+/// a `Backend`.
+#[derive(Clone, Debug)]
+pub struct LedgerSet {
+    repo: Repository,
+    local_path: PathBuf,
+    remote_name: String,
+    prefix: String,
+}
+
+impl LedgerSet {
+    /// A set of ledgers on the branches under `refs/heads/<prefix>` of
+    /// `remote_spec`, such as `tenants/`.
+    pub fn new(
+        local_path: PathBuf,
+        remote_spec: String,
+        remote_name: String,
+        prefix: String,
+    ) -> LedgerResult<LedgerSet> {
+        let repo = init_repo(&local_path, &remote_spec, &remote_name, true)?;
+        // Clear out refs left behind by writers that crashed mid-push.
+        if let Err(e) = sweep_tmp_refs(&repo) {
+            log::warn!("sweeping stale tmp refs: {:#}", e);
+        }
+        Ok(LedgerSet {
+            repo,
+            local_path,
+            remote_name,
+            prefix,
+        })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The ledger called `name`, on the branch `<prefix><name>`.
+    pub fn ledger(&self, name: &str) -> LedgerResult<GitLedger> {
+        let branch_name = format!("{}{}", self.prefix, name);
+        let valid = !name.is_empty()
+            && gix_ref::FullName::try_from(format!("refs/heads/{}", branch_name)).is_ok();
+        if !valid {
+            return Err(LedgerError::InvalidKey {
+                key: name.to_string(),
+                reason: "not a valid branch name",
+            });
+        }
+        Ok(GitLedger::in_set(
+            self.repo.clone(),
+            self.local_path.clone(),
+            &self.remote_name,
+            branch_name,
+        ))
+    }
+
+    /// Names of the ledgers that exist upstream, sorted.
+    pub fn list(&self) -> LedgerResult<Vec<String>> {
+        let branches = format!("refs/heads/{}", self.prefix);
+        let mut remote = self
+            .repo
+            .find_remote(self.remote_name.as_str())
+            .context("find remote")?;
+        let refspec = format!(
+            "{}*:refs/remotes/{}/{}*",
+            branches, self.remote_name, self.prefix
+        );
+        remote
+            .replace_refspecs(Some(refspec.as_str()), Direction::Fetch)
+            .context("narrow refspec")?;
+        let ref_map = remote
+            .connect(Direction::Fetch)
+            .map_err(|e| LedgerError::RemoteUnreachable(e.into()))?
+            .ref_map(DiscardProgress, gix::remote::ref_map::Options::default())
+            .map_err(|e| LedgerError::RemoteUnreachable(e.into()))?;
+
+        let mut names: Vec<_> = ref_map
+            .remote_refs
+            .iter()
+            .filter_map(|r| {
+                let (name, ..) = r.unpack();
+                name.to_string().strip_prefix(&branches).map(str::to_string)
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Fetch every ledger in `ledgers` at once, bringing each local branch up
+    /// to date as its own `fetch` would. The new states can then be read with
+    /// `fetch_with(Freshness::LocalOnly)`.
+    pub fn fetch(&self, ledgers: &[&GitLedger]) -> LedgerResult<()> {
+        if ledgers.is_empty() {
+            return Ok(());
+        }
+        self.check(ledgers)?;
+        let started = Instant::now();
+        for ledger in ledgers {
+            ledger.observers.fetch_started();
+        }
+
+        // Only fetch shallow if every ledger would.
+        let shallow = ledgers
+            .iter()
+            .map(|ledger| ledger.fetch_depth())
+            .collect::<Option<Vec<_>>>()
+            .and_then(|depths| depths.into_iter().max())
+            .map_or(Shallow::NoChange, Shallow::DepthAtRemote);
+        let branch_refs: Vec<_> = ledgers.iter().map(|ledger| ledger.branch_ref()).collect();
+        let lock = lock_refs(&self.repo)?;
+        let fetched = fetch_branches(&self.repo, &self.remote_name, &branch_refs, shallow);
+
+        let mut result = Ok(());
+        for ledger in ledgers {
+            let synced = match &fetched {
+                Ok(..) => ledger.sync_fetched(),
+                Err(e) => Err(e.duplicate()),
+            };
+            ledger
+                .observers
+                .fetch_finished(started.elapsed(), synced.as_ref().copied());
+            match synced {
+                Ok(()) => ledger.mark_synced(started),
+                Err(e) if result.is_ok() => result = Err(e),
+                Err(..) => {}
+            }
+        }
+        drop(lock);
+        fetched?;
+        result
+    }
+
+    /// Push a commit of each tree on top of its old commit, to its ledger, in
+    /// a single push. Each push lands or loses its race independently of the
+    /// others, and its outcome is returned in the same position.
+    pub fn push(
+        &self,
+        pushes: &[(&GitLedger, Option<ObjectId>, &TreeBuilder)],
+    ) -> LedgerResult<Vec<LedgerResult<PushOutcome>>> {
+        if pushes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ledgers: Vec<_> = pushes.iter().map(|(ledger, ..)| *ledger).collect();
+        self.check(&ledgers)?;
+        let started = Instant::now();
+        for ledger in &ledgers {
+            ledger.observers.push_started();
+        }
+        let results = self.push_once(pushes);
+        for ((ledger, expected, _), result) in pushes.iter().zip(&results) {
+            ledger
+                .observers
+                .push_finished(started.elapsed(), result.as_ref());
+            if let Ok(PushOutcome::RaceLost { remote, .. }) = result {
+                ledger.observers.race_lost(*expected, *remote);
+            }
+        }
+        Ok(results)
+    }
+
+    fn push_once(
+        &self,
+        pushes: &[(&GitLedger, Option<ObjectId>, &TreeBuilder)],
+    ) -> Vec<LedgerResult<PushOutcome>> {
+        let pending = pushes
+            .iter()
+            .map(|(ledger, old, tree)| ledger.write_pending(*old, tree, ledger.commit_info()))
+            .collect::<LedgerResult<Vec<_>>>();
+        let pending = match pending {
+            Ok(pending) => pending,
+            Err(e) => return pushes.iter().map(|_| Err(e.duplicate())).collect(),
+        };
+        let new_commit_ids: Vec<_> = pending.iter().map(|pending| pending.id).collect();
+
+        let updates: Vec<_> = pushes
+            .iter()
+            .zip(&new_commit_ids)
+            .map(|((ledger, old, _), new)| RefUpdate {
+                new: Some(*new),
+                dst_ref: ledger.branch_ref(),
+                expected: *old,
+            })
+            .collect();
+        let info = pushes[0].0.commit_info();
+        let pushed = push_each(&self.repo, &self.remote_name, &updates, info);
+        drop(pending);
+        let pushed = match pushed {
+            Ok(pushed) => pushed,
+            Err(e) => return pushes.iter().map(|_| Err(e.duplicate())).collect(),
+        };
+
+        // Find out which of the failed pushes lost races.
+        let failed: Vec<_> = pushes
+            .iter()
+            .zip(&pushed)
+            .filter(|(_, push)| !matches!(push, RefPush::Updated))
+            .map(|((ledger, ..), _)| *ledger)
+            .collect();
+        let refetched = if failed.is_empty() {
+            Ok(())
+        } else {
+            self.fetch(&failed)
+        };
+
+        pushes
+            .iter()
+            .zip(pushed)
+            .zip(new_commit_ids)
+            .map(|(((ledger, old, _), push), new)| {
+                let message = match push {
+                    RefPush::Updated => return Ok(PushOutcome::Committed(new)),
+                    RefPush::Stale => "remote branch moved during push".to_string(),
+                    RefPush::Rejected(message) => message,
+                };
+                if let Err(e) = &refetched {
+                    return Err(e.duplicate());
+                }
+                let remote = ledger.upstream_tip()?;
+                if remote != *old {
+                    return Ok(PushOutcome::RaceLost {
+                        expected: *old,
+                        remote,
+                    });
+                }
+                Err(LedgerError::RemoteRejected { message })
+            })
+            .collect()
+    }
+
+    /// Fail unless every ledger in `ledgers` belongs to this set, on a branch
+    /// of its own.
+    fn check(&self, ledgers: &[&GitLedger]) -> LedgerResult<()> {
+        let path = std::fs::canonicalize(self.repo.path()).context("resolve repository")?;
+        for (i, ledger) in ledgers.iter().enumerate() {
+            let other = std::fs::canonicalize(ledger.repo.path()).context("resolve repository")?;
+            if ledger.single_remote("a ledger set")? != Some(&self.remote_name) || other != path {
+                return Err(LedgerError::Repository(anyhow::anyhow!(
+                    "{} is not in this ledger set",
+                    ledger.branch_ref()
+                )));
+            }
+            if ledgers[..i]
+                .iter()
+                .any(|earlier| earlier.branch_ref() == ledger.branch_ref())
+            {
+                return Err(LedgerError::Repository(anyhow::anyhow!(
+                    "{} appears twice",
+                    ledger.branch_ref()
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{self, tree};
+    use crate::Freshness;
+
+    fn set(path: &std::path::Path, local: &str) -> LedgerSet {
+        LedgerSet::new(
+            path.join(local),
+            testing::upstream(path, "upstream"),
+            "origin".to_string(),
+            "tenants/".to_string(),
+        )
+        .unwrap()
+    }
+
+    fn local_tip(ledger: &GitLedger) -> Option<ObjectId> {
+        ledger
+            .fetch_with(Freshness::LocalOnly)
+            .unwrap()
+            .state
+            .map(|(commit, _)| commit.id)
+    }
+
+    #[test]
+    fn test_ledger_set() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let writer = set(tmp.path(), "local1");
+        assert!(writer.list().unwrap().is_empty());
+        let ledgers: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| writer.ledger(name).unwrap())
+            .collect();
+
+        let trees: Vec<_> = ledgers
+            .iter()
+            .map(|ledger| tree(&ledger.repo, ledger.branch_ref()))
+            .collect();
+        let pushes: Vec<_> = ledgers
+            .iter()
+            .zip(&trees)
+            .map(|(ledger, tree)| (ledger, None, tree))
+            .collect();
+        let first: Vec<_> = writer
+            .push(&pushes)
+            .unwrap()
+            .into_iter()
+            .map(|outcome| outcome.unwrap().committed().unwrap())
+            .collect();
+
+        // Branches outside the prefix are not in the set.
+        let other = GitLedger::new(
+            tmp.path().join("local1"),
+            tmp.path().join("upstream").to_string_lossy().to_string(),
+            "origin".to_string(),
+            "main".to_string(),
+        )
+        .unwrap();
+        other
+            .update_with(|repo, _| anyhow::Ok(tree(repo, "main")))
+            .unwrap();
+        assert_eq!(writer.list().unwrap(), ["a", "b", "c"]);
+
+        let reader = set(tmp.path(), "local2");
+        let a = reader.ledger("a").unwrap();
+        let b = reader.ledger("b").unwrap();
+        assert_eq!(local_tip(&a), None);
+        reader.fetch(&[&a, &b]).unwrap();
+        assert_eq!(local_tip(&a), Some(first[0]));
+        assert_eq!(local_tip(&b), Some(first[1]));
+
+        // One push losing its race does not hold up the others.
+        let moved = b
+            .update_with(|repo, _| anyhow::Ok(tree(repo, "moved")))
+            .unwrap();
+        let next = tree(&writer.repo, "next");
+        let outcomes = writer
+            .push(&[
+                (&ledgers[1], Some(first[1]), &next),
+                (&ledgers[2], Some(first[2]), &next),
+            ])
+            .unwrap();
+        assert_eq!(
+            outcomes[0].as_ref().unwrap(),
+            &PushOutcome::RaceLost {
+                expected: Some(first[1]),
+                remote: Some(moved),
+            }
+        );
+        let committed = outcomes[1].as_ref().unwrap().committed().unwrap();
+        assert_eq!(local_tip(&ledgers[1]), Some(moved));
+        reader.fetch(&[&reader.ledger("c").unwrap()]).unwrap();
+        assert_eq!(local_tip(&reader.ledger("c").unwrap()), Some(committed));
+    }
+
+    #[test]
+    fn test_ledger_set_ledgers() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledgers = set(tmp.path(), "local1");
+        assert!(matches!(
+            ledgers.ledger(""),
+            Err(LedgerError::InvalidKey { .. })
+        ));
+        assert!(matches!(
+            ledgers.ledger("a..b"),
+            Err(LedgerError::InvalidKey { .. })
+        ));
+
+        let a = ledgers.ledger("a").unwrap();
+        let foreign = set(tmp.path(), "local2").ledger("b").unwrap();
+        assert!(ledgers.fetch(&[&a, &foreign]).is_err());
+        assert!(ledgers.fetch(&[&a, &a]).is_err());
+    }
+}
@@ -1,6 +1,47 @@
+mod append_log;
+#[cfg(feature = "async")]
+mod asynchronous;
+mod backend;
+mod batch_ledger;
 mod blob_ledger;
+mod codec;
+mod commit;
+mod error;
+mod gc;
+mod history;
+mod kv_ledger;
 mod ledger;
+mod ledger_set;
+mod lock;
+mod observer;
+mod push;
+mod quorum;
+mod retry;
+mod signing;
+#[cfg(test)]
+mod testing;
+mod transaction;
+mod typed_ledger;
 mod util;
+mod watch;
 
+pub use append_log::*;
+#[cfg(feature = "async")]
+pub use asynchronous::*;
+pub use backend::*;
+pub use batch_ledger::*;
 pub use blob_ledger::*;
+pub use codec::*;
+pub use commit::*;
+pub use error::*;
+pub use history::*;
+pub use kv_ledger::*;
 pub use ledger::*;
+pub use ledger_set::*;
+pub use observer::*;
+pub use push::RefPush;
+pub use retry::*;
+pub use signing::*;
+pub use transaction::*;
+pub use typed_ledger::*;
+pub use watch::*;
@@ -0,0 +1,103 @@
+#[cfg(unix)]
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use gix::Repository;
+
+/// Exclusive lock on a file, held until dropped, that serializes work on a
+/// local repository between processes, and between ledgers in one process
+/// that opened the repository separately. Lock files are left in place, as
+/// removing one could let two processes lock different files of one name.
+///
+/// On unix this is `flock`, which the system releases if the holder dies.
+/// Elsewhere it is a gix lock file that a crashed holder leaves behind.
+#[derive(Debug)]
+pub(crate) struct FileLock {
+    #[cfg(unix)]
+    _file: File,
+    #[cfg(not(unix))]
+    _marker: gix::lock::Marker,
+}
+
+impl FileLock {
+    /// Wait for the lock on `path`, creating the file if needed.
+    #[cfg(unix)]
+    pub(crate) fn acquire(path: &Path) -> anyhow::Result<FileLock> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("open lock {}", path.display()))?;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+                return Ok(FileLock { _file: file });
+            }
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e).with_context(|| format!("lock {}", path.display()));
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn acquire(path: &Path) -> anyhow::Result<FileLock> {
+        use gix::lock::acquire::Fail;
+
+        let marker = gix::lock::Marker::acquire_to_hold_resource(
+            path,
+            Fail::AfterDurationWithBackoff(std::time::Duration::from_secs(60)),
+            None,
+        )
+        .with_context(|| format!("lock {}", path.display()))?;
+        Ok(FileLock { _marker: marker })
+    }
+}
+
+/// Lock creating or opening the repository at `local_path`, which may not
+/// exist yet, so the lock is `<local_path>.lock` beside it.
+pub(crate) fn lock_init(local_path: &Path) -> anyhow::Result<FileLock> {
+    if let Some(parent) = local_path.parent() {
+        std::fs::create_dir_all(parent).context("create parent directory")?;
+    }
+    let mut path = local_path.as_os_str().to_owned();
+    path.push(".lock");
+    FileLock::acquire(&PathBuf::from(path))
+}
+
+/// Lock fetching into and moving the ledger branches of `repo`.
+pub(crate) fn lock_refs(repo: &Repository) -> anyhow::Result<FileLock> {
+    FileLock::acquire(&repo.path().join("ledger.lock"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_file_lock() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let path = tmp.path().join("file.lock");
+        let held = Arc::new(AtomicBool::new(true));
+
+        let lock = FileLock::acquire(&path).unwrap();
+        let waiter = {
+            let (path, held) = (path.clone(), held.clone());
+            std::thread::spawn(move || {
+                let _lock = FileLock::acquire(&path).unwrap();
+                assert!(!held.load(Ordering::SeqCst));
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        held.store(false, Ordering::SeqCst);
+        drop(lock);
+        waiter.join().unwrap();
+    }
+}
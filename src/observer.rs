@@ -0,0 +1,292 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use gix_hash::ObjectId;
+
+use crate::error::{LedgerError, PushOutcome};
+
+/// Callbacks for ledger activity, registered with `GitLedger::with_observer`
+/// or `BlobGitLedger::with_observer`. Every method does nothing by default.
+///
+/// Callbacks run inline on the thread doing the work, so they should be
+/// quick and must not call back into the ledger.
+pub trait Observer: Send + Sync {
+    fn fetch_started(&self) {}
+
+    fn fetch_finished(&self, _elapsed: Duration, _result: Result<(), &LedgerError>) {}
+
+    fn push_started(&self) {}
+
+    fn push_finished(&self, _elapsed: Duration, _result: Result<&PushOutcome, &LedgerError>) {}
+
+    /// A push found upstream at `remote` rather than `expected`.
+    fn race_lost(&self, _expected: Option<ObjectId>, _remote: Option<ObjectId>) {}
+
+    /// `BlobGitLedger::lock` took `lease` after waiting `waited`, including
+    /// time spent on lost races.
+    fn lease_acquired(&self, _lease: u64, _waited: Duration) {}
+
+    /// `BlobGitLedger::lock` took over `lease`, which expired unreleased.
+    fn lease_stolen(&self, _lease: u64) {}
+
+    /// A guard replaced its lease with `lease`.
+    fn lease_renewed(&self, _lease: u64) {}
+
+    /// A guard found someone else had taken over `lease`.
+    fn lease_lost(&self, _lease: u64) {}
+}
+
+/// The observers registered on a ledger, called in registration order.
+#[derive(Clone, Default)]
+pub(crate) struct Observers(Vec<Arc<dyn Observer>>);
+
+impl Observers {
+    pub(crate) fn push(&mut self, observer: Arc<dyn Observer>) {
+        self.0.push(observer);
+    }
+
+    fn each(&self, f: impl Fn(&dyn Observer)) {
+        for observer in &self.0 {
+            f(observer.as_ref());
+        }
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl Observer for Observers {
+    fn fetch_started(&self) {
+        self.each(|o| o.fetch_started())
+    }
+
+    fn fetch_finished(&self, elapsed: Duration, result: Result<(), &LedgerError>) {
+        self.each(|o| o.fetch_finished(elapsed, result))
+    }
+
+    fn push_started(&self) {
+        self.each(|o| o.push_started())
+    }
+
+    fn push_finished(&self, elapsed: Duration, result: Result<&PushOutcome, &LedgerError>) {
+        self.each(|o| o.push_finished(elapsed, result))
+    }
+
+    fn race_lost(&self, expected: Option<ObjectId>, remote: Option<ObjectId>) {
+        self.each(|o| o.race_lost(expected, remote))
+    }
+
+    fn lease_acquired(&self, lease: u64, waited: Duration) {
+        self.each(|o| o.lease_acquired(lease, waited))
+    }
+
+    fn lease_stolen(&self, lease: u64) {
+        self.each(|o| o.lease_stolen(lease))
+    }
+
+    fn lease_renewed(&self, lease: u64) {
+        self.each(|o| o.lease_renewed(lease))
+    }
+
+    fn lease_lost(&self, lease: u64) {
+        self.each(|o| o.lease_lost(lease))
+    }
+}
+
+/// Number of histogram buckets. Bucket `i` counts durations of at most
+/// `2^i` milliseconds, and the last also everything longer.
+const BUCKETS: usize = 18;
+
+/// Duration histogram with power of two millisecond buckets.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    total_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let millis = duration.as_millis().max(1);
+        let bucket = (millis.next_power_of_two().trailing_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self
+                .buckets
+                .iter()
+                .enumerate()
+                .map(|(i, count)| (Duration::from_millis(1 << i), count.load(Ordering::Relaxed)))
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            total: Duration::from_micros(self.total_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Contents of a `Histogram` at one point in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Upper bound of each bucket, and how many durations fell in it. The
+    /// last bucket has no upper bound.
+    pub buckets: Vec<(Duration, u64)>,
+    pub count: u64,
+    pub total: Duration,
+}
+
+impl HistogramSnapshot {
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count).ok().filter(|&count| count > 0)?;
+        Some(self.total / count)
+    }
+
+    /// Upper bound of the bucket holding quantile `q`, from 0 to 1.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        self.buckets.iter().find_map(|&(bound, count)| {
+            seen += count;
+            (seen >= rank).then_some(bound)
+        })
+    }
+}
+
+/// Built-in `Observer` counting events and timing fetches, pushes and lease
+/// waits. Register it with `Arc::new`, keeping a clone of the `Arc` to read.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    fetches: AtomicU64,
+    failed_fetches: AtomicU64,
+    pushes: AtomicU64,
+    failed_pushes: AtomicU64,
+    races_lost: AtomicU64,
+    leases_acquired: AtomicU64,
+    leases_stolen: AtomicU64,
+    leases_renewed: AtomicU64,
+    leases_lost: AtomicU64,
+    fetch_time: Histogram,
+    push_time: Histogram,
+    lease_wait: Histogram,
+}
+
+/// Contents of `Metrics` at one point in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub fetches: u64,
+    pub failed_fetches: u64,
+    pub pushes: u64,
+    pub failed_pushes: u64,
+    pub races_lost: u64,
+    pub leases_acquired: u64,
+    pub leases_stolen: u64,
+    pub leases_renewed: u64,
+    pub leases_lost: u64,
+    pub fetch_time: HistogramSnapshot,
+    pub push_time: HistogramSnapshot,
+    pub lease_wait: HistogramSnapshot,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            fetches: load(&self.fetches),
+            failed_fetches: load(&self.failed_fetches),
+            pushes: load(&self.pushes),
+            failed_pushes: load(&self.failed_pushes),
+            races_lost: load(&self.races_lost),
+            leases_acquired: load(&self.leases_acquired),
+            leases_stolen: load(&self.leases_stolen),
+            leases_renewed: load(&self.leases_renewed),
+            leases_lost: load(&self.leases_lost),
+            fetch_time: self.fetch_time.snapshot(),
+            push_time: self.push_time.snapshot(),
+            lease_wait: self.lease_wait.snapshot(),
+        }
+    }
+}
+
+fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Observer for Metrics {
+    fn fetch_finished(&self, elapsed: Duration, result: Result<(), &LedgerError>) {
+        increment(&self.fetches);
+        if result.is_err() {
+            increment(&self.failed_fetches);
+        }
+        self.fetch_time.record(elapsed);
+    }
+
+    fn push_finished(&self, elapsed: Duration, result: Result<&PushOutcome, &LedgerError>) {
+        increment(&self.pushes);
+        if result.is_err() {
+            increment(&self.failed_pushes);
+        }
+        self.push_time.record(elapsed);
+    }
+
+    fn race_lost(&self, _expected: Option<ObjectId>, _remote: Option<ObjectId>) {
+        increment(&self.races_lost);
+    }
+
+    fn lease_acquired(&self, _lease: u64, waited: Duration) {
+        increment(&self.leases_acquired);
+        self.lease_wait.record(waited);
+    }
+
+    fn lease_stolen(&self, _lease: u64) {
+        increment(&self.leases_stolen);
+    }
+
+    fn lease_renewed(&self, _lease: u64) {
+        increment(&self.leases_renewed);
+    }
+
+    fn lease_lost(&self, _lease: u64) {
+        increment(&self.leases_lost);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.snapshot().mean(), None);
+        assert_eq!(histogram.snapshot().quantile(0.5), None);
+
+        for millis in [0, 1, 3, 3, 100, 1 << 20] {
+            histogram.record(Duration::from_millis(millis));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 6);
+        assert_eq!(snapshot.buckets[0], (Duration::from_millis(1), 2));
+        assert_eq!(snapshot.buckets[2], (Duration::from_millis(4), 2));
+        assert_eq!(snapshot.buckets[7], (Duration::from_millis(128), 1));
+        assert_eq!(snapshot.buckets[BUCKETS - 1].1, 1);
+        assert_eq!(snapshot.quantile(0.5), Some(Duration::from_millis(4)));
+        assert_eq!(snapshot.quantile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(
+            snapshot.mean(),
+            Some(Duration::from_micros((7 + 100 + (1 << 20)) * 1000 / 6))
+        );
+    }
+}
@@ -0,0 +1,429 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Context;
+use gix::object::Kind;
+use gix::prelude::{Find, Write};
+use gix::refs::transaction::{Change, LogChange, PreviousValue, RefEdit, RefLog};
+use gix::refs::Target;
+use gix::remote::Direction;
+use gix::Repository;
+use gix_hash::ObjectId;
+
+use crate::commit::CommitInfo;
+use crate::error::{LedgerError, LedgerResult};
+use crate::lock::lock_refs;
+
+/// Result of asking the remote to move one ref.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RefPush {
+    Updated,
+
+    /// The ref was not at the expected commit.
+    Stale,
+
+    /// The remote refused for some other reason, with its explanation.
+    Rejected(String),
+}
+
+/// Set `dst_ref` on the remote to `commit`, provided it currently points at
+/// `expected` (or does not exist, if `None`). Remotes on the local filesystem
+/// are written to directly with gix, which runs none of the remote's hooks
+/// and ignores its `receive.*` config. Other transports hand off to the git
+/// binary with the `subprocess-push` feature, on by default, and fail with
+/// `UnsupportedTransport` without it.
+pub(crate) fn push_commit(
+    repo: &Repository,
+    remote_name: &str,
+    commit: ObjectId,
+    dst_ref: &str,
+    expected: Option<ObjectId>,
+    info: &CommitInfo,
+) -> LedgerResult<RefPush> {
+    push_ref(repo, remote_name, Some(commit), dst_ref, expected, info)
+}
+
+/// As `push_commit`, deleting `dst_ref` if `new` is `None`.
+pub(crate) fn push_ref(
+    repo: &Repository,
+    remote_name: &str,
+    new: Option<ObjectId>,
+    dst_ref: &str,
+    expected: Option<ObjectId>,
+    info: &CommitInfo,
+) -> LedgerResult<RefPush> {
+    let update = RefUpdate {
+        new,
+        dst_ref,
+        expected,
+    };
+    push_refs(repo, remote_name, &[update], info)
+}
+
+/// One ref for `push_refs` to move from `expected` to `new`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RefUpdate<'a> {
+    pub new: Option<ObjectId>,
+    pub dst_ref: &'a str,
+    pub expected: Option<ObjectId>,
+}
+
+/// As `push_ref`, moving every ref in `updates` atomically: either all of
+/// them are at their expected commits and all move, or none do.
+pub(crate) fn push_refs(
+    repo: &Repository,
+    remote_name: &str,
+    updates: &[RefUpdate<'_>],
+    info: &CommitInfo,
+) -> LedgerResult<RefPush> {
+    let pushed = push_many(repo, remote_name, updates, true, info)?;
+    // Refs that were fine are rejected too when another is stale.
+    if pushed.iter().any(|push| matches!(push, RefPush::Stale)) {
+        return Ok(RefPush::Stale);
+    }
+    Ok(pushed
+        .into_iter()
+        .find(|push| matches!(push, RefPush::Rejected(..)))
+        .unwrap_or(RefPush::Updated))
+}
+
+/// As `push_ref` for every ref in `updates` at once, each moving or not
+/// independently of the others, returning the result for each in order.
+pub(crate) fn push_each(
+    repo: &Repository,
+    remote_name: &str,
+    updates: &[RefUpdate<'_>],
+    info: &CommitInfo,
+) -> LedgerResult<Vec<RefPush>> {
+    push_many(repo, remote_name, updates, false, info)
+}
+
+fn push_many(
+    repo: &Repository,
+    remote_name: &str,
+    updates: &[RefUpdate<'_>],
+    atomic: bool,
+    info: &CommitInfo,
+) -> LedgerResult<Vec<RefPush>> {
+    let remote = repo.find_remote(remote_name).context("find remote")?;
+    let url = remote
+        .url(Direction::Push)
+        .context("remote has no url")?
+        .clone();
+
+    if url.scheme == gix::url::Scheme::File {
+        let upstream_path = gix::path::from_bstring(url.path.clone());
+        return push_local(repo, &upstream_path, updates, atomic, info);
+    }
+
+    #[cfg(feature = "subprocess-push")]
+    {
+        push_subprocess(repo, remote_name, updates, atomic, info)
+    }
+
+    #[cfg(not(feature = "subprocess-push"))]
+    {
+        Err(LedgerError::UnsupportedTransport(
+            url.to_bstring().to_string(),
+        ))
+    }
+}
+
+fn push_local(
+    repo: &Repository,
+    upstream_path: &Path,
+    updates: &[RefUpdate<'_>],
+    atomic: bool,
+    info: &CommitInfo,
+) -> LedgerResult<Vec<RefPush>> {
+    let upstream =
+        gix::open(upstream_path).map_err(|e| LedgerError::RemoteUnreachable(e.into()))?;
+    for commit in updates.iter().filter_map(|update| update.new) {
+        copy_objects(repo, &upstream, commit)?;
+    }
+    // gix reads the old value of a ref before locking it, so two pushes can
+    // both see the value they expect and the second overwrite the first.
+    let _lock = lock_refs(&upstream)?;
+    if atomic {
+        let pushed = update_refs(&upstream, updates, info)?;
+        return Ok(vec![pushed; updates.len()]);
+    }
+    updates
+        .iter()
+        .map(|update| update_refs(&upstream, std::slice::from_ref(update), info))
+        .collect()
+}
+
+/// Compare-and-swap `dst_ref` in `repo` from `expected` to `new`, which must
+/// already be in `repo`, deleting it if `new` is `None`.
+pub(crate) fn update_ref(
+    repo: &Repository,
+    new: Option<ObjectId>,
+    dst_ref: &str,
+    expected: Option<ObjectId>,
+    info: &CommitInfo,
+) -> LedgerResult<RefPush> {
+    let update = RefUpdate {
+        new,
+        dst_ref,
+        expected,
+    };
+    update_refs(repo, &[update], info)
+}
+
+/// As `update_ref` for every ref in `updates` in one transaction, which
+/// locks them all before checking any.
+pub(crate) fn update_refs(
+    repo: &Repository,
+    updates: &[RefUpdate<'_>],
+    info: &CommitInfo,
+) -> LedgerResult<RefPush> {
+    let mut edits = Vec::new();
+    for update in updates {
+        let expected = match update.expected {
+            Some(id) => PreviousValue::MustExistAndMatch(Target::Peeled(id)),
+            None => PreviousValue::MustNotExist,
+        };
+        let change = match update.new {
+            Some(commit) => Change::Update {
+                log: LogChange {
+                    mode: RefLog::AndReference,
+                    force_create_reflog: false,
+                    message: "push".into(),
+                },
+                expected,
+                new: Target::Peeled(commit),
+            },
+            None => Change::Delete {
+                expected,
+                log: RefLog::AndReference,
+            },
+        };
+        edits.push(RefEdit {
+            change,
+            name: update.dst_ref.try_into().context("ref name")?,
+            deref: false,
+        });
+    }
+
+    use gix::lock::acquire::Fail;
+    use gix::refs::file::transaction::prepare::Error as PrepareError;
+    let transaction =
+        match repo
+            .refs
+            .transaction()
+            .prepare(edits, Fail::Immediately, Fail::Immediately)
+        {
+            Ok(transaction) => transaction,
+            Err(
+                PrepareError::MustNotExist { .. }
+                | PrepareError::MustExist { .. }
+                | PrepareError::ReferenceOutOfDate { .. }
+                | PrepareError::LockAcquire { .. },
+            ) => return Ok(RefPush::Stale),
+            Err(e) => return Ok(RefPush::Rejected(e.to_string())),
+        };
+    match transaction.commit(Some(info.committer_signature(info.time()))) {
+        Ok(..) => Ok(RefPush::Updated),
+        Err(e) => Ok(RefPush::Rejected(e.to_string())),
+    }
+}
+
+/// Write everything reachable from `commit` that `dst` lacks. Objects are
+/// written dependencies first, so an interrupted copy never leaves an object
+/// in `dst` whose referents are missing.
+fn copy_objects(src: &Repository, dst: &Repository, commit: ObjectId) -> LedgerResult<()> {
+    let find = |id| {
+        let object = src.find_object(id).context("find object to push")?;
+        Ok((object.kind, object.detach().data))
+    };
+    for id in missing_objects(commit, |id| dst.objects.contains(id), find)? {
+        let object = src.find_object(id).context("find object to push")?;
+        dst.objects
+            .write_buf(object.kind, &object.data)
+            .map_err(|e| LedgerError::RemoteUnreachable(anyhow::anyhow!(e)))?;
+    }
+    Ok(())
+}
+
+/// Everything reachable from `commit` that `contains` says is missing,
+/// dependencies first, reading each object with `find`.
+pub(crate) fn missing_objects(
+    commit: ObjectId,
+    contains: impl Fn(ObjectId) -> bool,
+    find: impl Fn(ObjectId) -> LedgerResult<(Kind, Vec<u8>)>,
+) -> LedgerResult<Vec<ObjectId>> {
+    let mut pending = vec![commit];
+    let mut seen = HashSet::new();
+    let mut missing = Vec::new();
+    while let Some(id) = pending.pop() {
+        if !seen.insert(id) || contains(id) {
+            continue;
+        }
+        let (kind, data) = find(id)?;
+        match kind {
+            Kind::Commit => {
+                let commit = gix_object::CommitRef::from_bytes(&data).context("decode commit")?;
+                pending.push(commit.tree());
+                pending.extend(commit.parents());
+            }
+            Kind::Tree => {
+                let tree = gix_object::TreeRef::from_bytes(&data).context("decode tree")?;
+                pending.extend(
+                    tree.entries
+                        .iter()
+                        .filter(|entry| entry.mode != gix_object::tree::EntryMode::Commit)
+                        .map(|entry| ObjectId::from(entry.oid)),
+                );
+            }
+            Kind::Blob | Kind::Tag => {}
+        }
+        missing.push(id);
+    }
+    missing.reverse();
+    Ok(missing)
+}
+
+#[cfg(feature = "subprocess-push")]
+fn push_subprocess(
+    repo: &Repository,
+    remote_name: &str,
+    updates: &[RefUpdate<'_>],
+    atomic: bool,
+    info: &CommitInfo,
+) -> LedgerResult<Vec<RefPush>> {
+    let mut command = crate::util::git_command(info);
+    command
+        .current_dir(repo.path())
+        .arg("push")
+        .arg("--porcelain");
+    if atomic && updates.len() > 1 {
+        command.arg("--atomic");
+    }
+    for update in updates {
+        command.arg(match update.expected {
+            Some(id) => format!("--force-with-lease={}:{}", update.dst_ref, id),
+            None => format!("--force-with-lease={}:", update.dst_ref),
+        });
+    }
+    command.arg(remote_name);
+    for update in updates {
+        let src = update
+            .new
+            .map(|commit| commit.to_string())
+            .unwrap_or_default();
+        command.arg(format!("{}:{}", src, update.dst_ref));
+    }
+    let output = command
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .output()
+        .context("subprocess failed")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // Porcelain output reports each ref as "<flag>\t<src>:<dst>\t<summary>".
+    let mut pushed = vec![None; updates.len()];
+    for line in stdout.lines() {
+        let mut fields = line.split('\t');
+        let (flag, refs, summary) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(flag), Some(refs), Some(summary)) => (flag, refs, summary),
+            _ => continue,
+        };
+        let Some(i) = updates
+            .iter()
+            .position(|update| refs.ends_with(&format!(":{}", update.dst_ref)))
+        else {
+            continue;
+        };
+        pushed[i] = Some(match flag {
+            "!" if summary.contains("stale info") => RefPush::Stale,
+            "!" => RefPush::Rejected(format!("{} {}", summary, stderr.trim())),
+            _ => RefPush::Updated,
+        });
+    }
+
+    if output.status.success() || pushed.iter().any(Option::is_some) {
+        return Ok(pushed
+            .into_iter()
+            .map(|push| {
+                push.unwrap_or_else(|| {
+                    if output.status.success() {
+                        RefPush::Updated
+                    } else {
+                        RefPush::Rejected(stderr.trim().to_string())
+                    }
+                })
+            })
+            .collect());
+    }
+    Err(LedgerError::RemoteUnreachable(anyhow::anyhow!(
+        "git push failed: {}",
+        stderr.trim()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_objects() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let src = gix::init_bare(tmp.path().join("src")).unwrap();
+        let dst = gix::init_bare(tmp.path().join("dst")).unwrap();
+
+        let blob = src.write_blob(b"contents").unwrap().detach();
+        let mut tb = gix_object::Tree::empty();
+        tb.entries.push(gix_object::tree::Entry {
+            oid: blob,
+            mode: gix_object::tree::EntryMode::Blob,
+            filename: "file".into(),
+        });
+        let tree = src.write_object(&tb).unwrap().detach();
+        let sig = gix::actor::SignatureRef::default();
+        let commit = src
+            .commit_as(
+                sig,
+                sig,
+                "refs/heads/main",
+                "message",
+                tree,
+                None::<ObjectId>,
+            )
+            .unwrap()
+            .detach();
+
+        assert!(!dst.objects.contains(commit));
+        copy_objects(&src, &dst, commit).unwrap();
+        for id in [commit, tree, blob] {
+            assert!(dst.objects.contains(id));
+        }
+    }
+
+    #[cfg(not(feature = "subprocess-push"))]
+    #[test]
+    fn test_unsupported_transport() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let repo = crate::util::init_repo(
+            &tmp.path().join("local"),
+            "https://example.invalid/ledger.git",
+            "origin",
+            true,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            push_commit(
+                &repo,
+                "origin",
+                ObjectId::null(gix_hash::Kind::Sha1),
+                "refs/heads/main",
+                None,
+                &CommitInfo::default()
+            ),
+            Err(LedgerError::UnsupportedTransport(..))
+        ));
+    }
+}
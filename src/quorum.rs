@@ -0,0 +1,487 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use anyhow::Context;
+use gix::remote::fetch::Shallow;
+use gix::Repository;
+use gix_hash::ObjectId;
+
+use crate::commit::CommitInfo;
+use crate::error::{LedgerError, LedgerResult};
+use crate::ledger::fetch_remote;
+use crate::push::{push_commit, push_ref, RefPush};
+use crate::util::*;
+
+/// Most remotes one replicated ledger may use, so that the set of replicas
+/// holding a commit fits in a bit mask.
+pub(crate) const MAX_REPLICAS: usize = 64;
+
+/// One of the remotes a replicated ledger is kept on.
+#[derive(Clone, Debug)]
+pub(crate) struct Replica {
+    pub(crate) name: String,
+    pub(crate) tracking_ref: String,
+}
+
+fn majority(replicas: &[Replica]) -> usize {
+    replicas.len() / 2 + 1
+}
+
+/// Fetch every reachable replica, move the local branch to the newest commit
+/// held by a majority of them, and bring replicas that lag behind it or hold
+/// writes that can never be committed up to date.
+///
+/// Replicas only ever fast forward, except when a writer takes back a commit
+/// that failed to reach a majority, or a replica is found on a fork that
+/// does not contain a commit a majority already holds. Commits held by a
+/// majority therefore stay held by a majority, and always form one line.
+pub(crate) fn fetch_replicas(
+    repo: &Repository,
+    replicas: &[Replica],
+    branch_ref: &str,
+    info: &CommitInfo,
+    verify: impl Fn(ObjectId) -> LedgerResult<()>,
+) -> LedgerResult<()> {
+    let mut reachable = Vec::new();
+    let mut unreachable = None;
+    for replica in replicas {
+        match fetch_remote(repo, &replica.name, branch_ref, Shallow::NoChange) {
+            Ok(tip) => {
+                // Fetch does not prune, so forget branches taken back by a
+                // writer that failed to reach a majority.
+                if tip.is_none() {
+                    if let Some(tracking) = repo
+                        .try_find_reference(replica.tracking_ref.as_str())
+                        .context("find tracking branch")?
+                    {
+                        tracking.delete().context("delete tracking branch")?;
+                    }
+                }
+                reachable.push((replica, tip));
+            }
+            Err(LedgerError::RemoteUnreachable(e)) => {
+                log::trace!("fetch_replicas: {} unreachable: {:#}", replica.name, e);
+                unreachable = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let needed = majority(replicas);
+    if reachable.len() < needed {
+        let e = unreachable.unwrap_or_else(|| anyhow::anyhow!("no replicas"));
+        return Err(LedgerError::RemoteUnreachable(e.context(format!(
+            "reached {} of {} replicas, need {}",
+            reachable.len(),
+            replicas.len(),
+            needed
+        ))));
+    }
+
+    let tips: Vec<_> = reachable.iter().map(|(_, tip)| *tip).collect();
+    let quorum = quorum_tip(repo, &tips, needed)?;
+    let local = peeled_only(repo.refs.try_find(branch_ref).context("find")?)?;
+    let target = match (local, quorum) {
+        (local, None) => local,
+        (Some(local), Some(quorum)) if local == quorum => Some(local),
+        (local, Some(quorum))
+            if local.map_or(Ok(true), |local| is_ancestor(repo, local, quorum))? =>
+        {
+            verify(quorum)?;
+            if !fast_forward(repo, branch_ref, quorum)? {
+                return Err(LedgerError::TrackingDiverged {
+                    local: local.context("local branch vanished")?,
+                    remote: quorum,
+                });
+            }
+            Some(quorum)
+        }
+        // Too few of the replicas that hold what we saw last time answered.
+        (Some(local), Some(quorum)) if is_ancestor(repo, quorum, local)? => Some(local),
+        (local, Some(quorum)) => {
+            verify(quorum)?;
+            return Err(LedgerError::TrackingDiverged {
+                local: local.context("local branch vanished")?,
+                remote: quorum,
+            });
+        }
+    };
+    let target = match target {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+
+    for (replica, tip) in reachable {
+        let repair = match tip {
+            None => true,
+            Some(tip) if tip == target => false,
+            Some(tip) if is_ancestor(repo, tip, target)? => true,
+            // Ahead of `target`, perhaps with a write still in flight.
+            Some(tip) if is_ancestor(repo, target, tip)? => false,
+            // On a fork that can never reach a majority, as a majority
+            // already holds `target`.
+            Some(..) => true,
+        };
+        if !repair {
+            continue;
+        }
+        match push_commit(repo, &replica.name, target, branch_ref, tip, info) {
+            Ok(RefPush::Updated) => log::trace!("fetch_replicas: repaired {}", replica.name),
+            Ok(outcome) => log::trace!(
+                "fetch_replicas: {} not repaired: {:?}",
+                replica.name,
+                outcome
+            ),
+            Err(e) => log::trace!("fetch_replicas: {} not repaired: {}", replica.name, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Offer `commit` to every replica, replacing `expected`. Replicas that lag
+/// behind `expected` are fast forwarded past it. Unless a majority accepts,
+/// `commit` is taken back from the replicas that did.
+pub(crate) fn push_replicas(
+    repo: &Repository,
+    replicas: &[Replica],
+    commit: ObjectId,
+    branch_ref: &str,
+    expected: Option<ObjectId>,
+    info: &CommitInfo,
+) -> LedgerResult<RefPush> {
+    let mut accepted = Vec::new();
+    let mut stale = false;
+    let mut rejected = None;
+    let mut unreachable = None;
+    for replica in replicas {
+        let tip = peeled_only(repo.refs.try_find(&replica.tracking_ref).context("find")?)?;
+        let lease = match (tip, expected) {
+            (Some(tip), Some(expected)) if tip != expected && is_ancestor(repo, tip, expected)? => {
+                Some(tip)
+            }
+            (None, Some(..)) => None,
+            _ => expected,
+        };
+        match push_commit(repo, &replica.name, commit, branch_ref, lease, info) {
+            Ok(RefPush::Updated) => accepted.push((replica, lease)),
+            Ok(RefPush::Stale) => stale = true,
+            Ok(RefPush::Rejected(message)) => rejected = Some(message),
+            Err(e) => {
+                log::trace!("push_replicas: {} failed: {}", replica.name, e);
+                unreachable = Some(e);
+            }
+        }
+    }
+
+    if accepted.len() >= majority(replicas) {
+        return Ok(RefPush::Updated);
+    }
+
+    // Nobody can have seen `commit` as committed, so it is safe to take back,
+    // and left in place it would stop those replicas accepting other writes.
+    for (replica, lease) in accepted {
+        match push_ref(repo, &replica.name, lease, branch_ref, Some(commit), info) {
+            Ok(RefPush::Updated) => {}
+            Ok(outcome) => log::trace!("push_replicas: {} kept write: {:?}", replica.name, outcome),
+            Err(e) => log::trace!("push_replicas: {} kept write: {}", replica.name, e),
+        }
+    }
+
+    match (stale, rejected, unreachable) {
+        (true, ..) => Ok(RefPush::Stale),
+        (false, Some(message), _) => Ok(RefPush::Rejected(message)),
+        (false, None, Some(e)) => Err(e),
+        (false, None, None) => Ok(RefPush::Rejected(
+            "too few replicas accepted the push".to_string(),
+        )),
+    }
+}
+
+/// The newest commit contained in at least `needed` of `tips`.
+///
+/// Walks back from all the tips at once, newest first, marking each commit
+/// with the tips it is reachable from, as `git merge-base` does. Commit times
+/// only guide the order: as with merge-base, the walk continues until every
+/// queued commit is an ancestor of a candidate, so ties and skewed clocks
+/// cannot hide a newer candidate.
+fn quorum_tip(
+    repo: &Repository,
+    tips: &[Option<ObjectId>],
+    needed: usize,
+) -> LedgerResult<Option<ObjectId>> {
+    let mut marks: HashMap<ObjectId, u64> = HashMap::new();
+    let mut stale = HashSet::new();
+    let mut candidates = Vec::new();
+    let mut queue = BinaryHeap::new();
+    for (i, tip) in tips.iter().enumerate() {
+        if let Some(tip) = *tip {
+            *marks.entry(tip).or_default() |= 1 << i;
+            queue.push((commit_time(repo, tip)?, tip));
+        }
+    }
+
+    while queue.iter().any(|(_, id)| !stale.contains(id)) {
+        let (_, id) = queue.pop().expect("queue is not empty");
+        let mark = marks[&id];
+        let is_stale = stale.contains(&id);
+        if !is_stale && mark.count_ones() as usize >= needed {
+            candidates.push(id);
+            stale.insert(id);
+        }
+        let is_stale = stale.contains(&id);
+        for parent in find_commit(repo, id)?.parent_ids() {
+            let parent = parent.detach();
+            let parent_mark = marks.entry(parent).or_default();
+            let grew = *parent_mark | mark != *parent_mark;
+            *parent_mark |= mark;
+            let newly_stale = is_stale && stale.insert(parent);
+            if grew || newly_stale {
+                queue.push((commit_time(repo, parent)?, parent));
+            }
+        }
+    }
+
+    // Majorities overlap, so the candidates lie on one line unless the
+    // replicas hold merges; prefer the newest of those not below another.
+    let mut best: Option<(u32, ObjectId)> = None;
+    for &candidate in &candidates {
+        let mut below_another = false;
+        for &other in &candidates {
+            if other != candidate && is_ancestor(repo, candidate, other)? {
+                below_another = true;
+                break;
+            }
+        }
+        let time = commit_time(repo, candidate)?;
+        if !below_another && best.is_none_or(|best| time > best.0) {
+            best = Some((time, candidate));
+        }
+    }
+    Ok(best.map(|(_, id)| id))
+}
+
+fn find_commit(repo: &Repository, id: ObjectId) -> LedgerResult<gix::Commit<'_>> {
+    repo.find_object(id)
+        .context("find commit")?
+        .try_into_commit()
+        .map_err(|e| LedgerError::CorruptLedger(e.to_string()))
+}
+
+fn commit_time(repo: &Repository, id: ObjectId) -> LedgerResult<u32> {
+    Ok(find_commit(repo, id)?
+        .time()
+        .context("commit time")?
+        .seconds())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use crate::testing::{self, tree as tree_with};
+    use crate::{GitLedger, PushOutcome};
+
+    const REPLICAS: usize = 3;
+
+    fn make_ledger(path: &Path, j: usize) -> GitLedger {
+        let remotes = (0..REPLICAS)
+            .map(|i| {
+                (
+                    format!("replica{}", i),
+                    testing::upstream(path, &format!("upstream{}", i)),
+                )
+            })
+            .collect();
+        GitLedger::replicated(
+            path.join(format!("local{}", j)),
+            remotes,
+            "main".to_string(),
+        )
+        .unwrap()
+    }
+
+    /// A ledger on a single replica, for writing behind the quorum's back.
+    fn make_rogue(path: &Path, i: usize) -> GitLedger {
+        GitLedger::new(
+            path.join(format!("rogue{}", i)),
+            testing::upstream(path, &format!("upstream{}", i)),
+            "origin".to_string(),
+            "main".to_string(),
+        )
+        .unwrap()
+    }
+
+    fn upstream_tip(path: &Path, i: usize) -> Option<ObjectId> {
+        let upstream = gix::open(path.join(format!("upstream{}", i))).unwrap();
+        peeled_only(upstream.refs.try_find("refs/heads/main").unwrap()).unwrap()
+    }
+
+    fn increment(ledger: &GitLedger) -> ObjectId {
+        ledger
+            .update_with(|repo, st| {
+                let count: u64 = match st {
+                    None => 0,
+                    Some((_commit, tree)) => {
+                        let entry = tree.lookup_entry_by_path("single")?.unwrap();
+                        std::str::from_utf8(&entry.object()?.data)?.parse()?
+                    }
+                };
+                anyhow::Ok(tree_with(repo, &(count + 1).to_string()))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_quorum_tip() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let repo = gix::init_bare(tmp.path()).unwrap();
+        let commit = |parent: Option<ObjectId>, contents: &str| {
+            let tree = repo.write_object(tree_with(&repo, contents)).unwrap();
+            crate::commit::write_commit(
+                &repo,
+                &format!("refs/heads/{}", contents),
+                tree.detach(),
+                parent,
+                &CommitInfo::default(),
+            )
+            .unwrap()
+        };
+        let base = commit(None, "base");
+        let left = commit(Some(base), "left");
+        let right = commit(Some(base), "right");
+        let next = commit(Some(left), "next");
+
+        assert_eq!(quorum_tip(&repo, &[None, None, None], 2).unwrap(), None);
+        assert_eq!(
+            quorum_tip(&repo, &[Some(left), Some(right), None], 2).unwrap(),
+            Some(base)
+        );
+        assert_eq!(
+            quorum_tip(&repo, &[Some(next), Some(right), Some(left)], 2).unwrap(),
+            Some(left)
+        );
+        assert_eq!(
+            quorum_tip(&repo, &[Some(next), Some(next), Some(base)], 3).unwrap(),
+            Some(base)
+        );
+    }
+
+    #[test]
+    fn test_replicated() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger1 = make_ledger(tmp.path(), 1);
+        let ledger2 = make_ledger(tmp.path(), 2);
+        assert!(ledger1.fetch().unwrap().is_none());
+
+        let mut last = None;
+        for i in 0..4 {
+            last = Some(increment(if i % 2 == 0 { &ledger1 } else { &ledger2 }));
+        }
+        for i in 0..REPLICAS {
+            assert_eq!(upstream_tip(tmp.path(), i), last);
+        }
+        assert_eq!(ledger2.fetch().unwrap().map(|(commit, _)| commit.id), last);
+    }
+
+    #[test]
+    fn test_replica_down() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger = make_ledger(tmp.path(), 1);
+        increment(&ledger);
+
+        let up = tmp.path().join("upstream2");
+        let down = tmp.path().join("upstream2.down");
+        std::fs::rename(&up, &down).unwrap();
+        let latest = increment(&ledger);
+        assert_ne!(upstream_tip(tmp.path(), 0), None);
+        std::fs::rename(&down, &up).unwrap();
+        assert_ne!(upstream_tip(tmp.path(), 2), Some(latest));
+
+        // Fetch brings the lagging replica up to date.
+        ledger.fetch().unwrap();
+        assert_eq!(upstream_tip(tmp.path(), 2), Some(latest));
+
+        std::fs::rename(
+            tmp.path().join("upstream0"),
+            tmp.path().join("upstream0.down"),
+        )
+        .unwrap();
+        std::fs::rename(&up, &down).unwrap();
+        assert!(matches!(
+            ledger.fetch(),
+            Err(LedgerError::RemoteUnreachable(..))
+        ));
+    }
+
+    #[test]
+    fn test_minority_write() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger = make_ledger(tmp.path(), 1);
+        let base = increment(&ledger);
+
+        // A write that only reached one replica is not part of the ledger.
+        let rogue = make_rogue(tmp.path(), 0);
+        rogue.fetch().unwrap();
+        let forged = rogue
+            .push(Some(base), &tree_with(&rogue.repo, "forged"))
+            .unwrap()
+            .committed()
+            .unwrap();
+        let (commit, _tree) = ledger.fetch().unwrap().unwrap();
+        assert_eq!(commit.id, base);
+        assert_eq!(upstream_tip(tmp.path(), 0), Some(forged));
+
+        // Once the majority moves on, the dead fork is replaced.
+        let latest = increment(&ledger);
+        ledger.fetch().unwrap();
+        for i in 0..REPLICAS {
+            assert_eq!(upstream_tip(tmp.path(), i), Some(latest));
+        }
+    }
+
+    #[test]
+    fn test_lost_majority() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger = make_ledger(tmp.path(), 1);
+        let base = increment(&ledger);
+        ledger.fetch().unwrap();
+
+        // Another writer wins replicas 1 and 2 first.
+        let rival = GitLedger::replicated(
+            tmp.path().join("rival"),
+            (1..REPLICAS)
+                .map(|i| {
+                    (
+                        format!("replica{}", i),
+                        tmp.path()
+                            .join(format!("upstream{}", i))
+                            .to_string_lossy()
+                            .to_string(),
+                    )
+                })
+                .collect(),
+            "main".to_string(),
+        )
+        .unwrap();
+        rival.fetch().unwrap();
+        let won = rival
+            .push(Some(base), &tree_with(&rival.repo, "rival"))
+            .unwrap()
+            .committed()
+            .unwrap();
+
+        assert_eq!(
+            ledger
+                .push(Some(base), &tree_with(&ledger.repo, "ours"))
+                .unwrap(),
+            PushOutcome::RaceLost {
+                expected: Some(base),
+                remote: Some(won),
+            }
+        );
+        // Our write was taken back from replica 0, which then caught up.
+        assert_eq!(upstream_tip(tmp.path(), 0), Some(won));
+    }
+}
@@ -0,0 +1,247 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::error::{LedgerError, LedgerResult};
+
+/// How `update_with` and `BlobGitLedger::lock` retry after losing a race:
+/// exponential backoff with jitter, optionally bounded by a number of attempts
+/// and an overall deadline. Once either bound is reached the call fails with
+/// `LedgerError::RetriesExhausted`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Delay after the first lost race.
+    pub initial_backoff: Duration,
+
+    /// Upper bound on the delay between attempts.
+    pub max_backoff: Duration,
+
+    /// Factor the delay grows by after each lost race. Anything below one,
+    /// NaN included, is taken as one.
+    pub multiplier: f64,
+
+    /// Fraction of each delay, between 0 and 1, that is randomized so
+    /// contending writers fall out of lockstep. Anything outside that range
+    /// is clamped to it, and NaN is taken as 0.
+    pub jitter: f64,
+
+    /// Total attempts before giving up, if limited.
+    pub max_attempts: Option<u32>,
+
+    /// Time from the start of the call after which no further attempts are
+    /// made, if limited.
+    pub deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Retry immediately and forever.
+    pub fn immediate() -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            multiplier: 1.0,
+            jitter: 0.0,
+            max_attempts: None,
+            deadline: None,
+        }
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    pub fn with_multiplier(mut self, multiplier: f64) -> RetryPolicy {
+        self.multiplier = growth(multiplier);
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> RetryPolicy {
+        self.jitter = fraction(jitter);
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> RetryPolicy {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Duration) -> RetryPolicy {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Begin tracking attempts for one call.
+    pub(crate) fn start(&self) -> Retry<'_> {
+        Retry {
+            policy: self,
+            attempts: 0,
+            start_time: Instant::now(),
+            backoff: self.initial_backoff,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Unbounded retries with jittered backoff from 10ms up to one second.
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+            deadline: None,
+        }
+    }
+}
+
+/// Progress of one call through a `RetryPolicy`.
+pub(crate) struct Retry<'a> {
+    policy: &'a RetryPolicy,
+    attempts: u32,
+    start_time: Instant,
+    backoff: Duration,
+}
+
+impl<'a> Retry<'a> {
+    /// Record a failed attempt, returning how long to wait before the next,
+    /// or an error if the policy does not allow another.
+    pub(crate) fn failed(&mut self) -> LedgerResult<Duration> {
+        self.attempts += 1;
+        if let Some(max_attempts) = self.policy.max_attempts {
+            if self.attempts >= max_attempts {
+                return Err(self.exhausted());
+            }
+        }
+
+        let jitter = fraction(self.policy.jitter) * rand::thread_rng().gen::<f64>();
+        let delay = self.backoff.mul_f64(1.0 - jitter);
+        // A backoff too long to represent is past the bound anyway.
+        self.backoff = Duration::try_from_secs_f64(
+            self.backoff.as_secs_f64() * growth(self.policy.multiplier),
+        )
+        .unwrap_or(self.policy.max_backoff)
+        .min(self.policy.max_backoff);
+        log::trace!("Attempt {} failed; retrying in {:?}", self.attempts, delay);
+
+        match self.remaining() {
+            Some(remaining) if remaining <= delay => Err(self.exhausted()),
+            _ => Ok(delay),
+        }
+    }
+
+    /// Clamp a wait that is not itself a retry, such as polling for a lease,
+    /// to the deadline.
+    pub(crate) fn wait(&self, duration: Duration) -> LedgerResult<Duration> {
+        match self.remaining() {
+            Some(remaining) if remaining.is_zero() => Err(self.exhausted()),
+            Some(remaining) => Ok(duration.min(remaining)),
+            None => Ok(duration),
+        }
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        self.policy
+            .deadline
+            .map(|deadline| deadline.saturating_sub(self.start_time.elapsed()))
+    }
+
+    fn exhausted(&self) -> LedgerError {
+        LedgerError::RetriesExhausted {
+            attempts: self.attempts,
+            elapsed: self.start_time.elapsed(),
+        }
+    }
+}
+
+/// `multiplier` as a factor of at least one. `max` ignores NaN.
+fn growth(multiplier: f64) -> f64 {
+    multiplier.max(1.0)
+}
+
+/// `jitter` as a fraction between 0 and 1. `clamp` would keep NaN.
+fn fraction(jitter: f64) -> f64 {
+    if jitter.is_nan() {
+        0.0
+    } else {
+        jitter.clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(35))
+            .with_jitter(0.0);
+        let mut retry = policy.start();
+        let delays: Vec<_> = (0..4).map(|_| retry.failed().unwrap()).collect();
+        assert_eq!(delays, [10, 20, 35, 35].map(Duration::from_millis).to_vec());
+
+        let policy = policy.with_jitter(0.5);
+        let mut retry = policy.start();
+        for _ in 0..10 {
+            let delay = retry.failed().unwrap();
+            assert!(delay <= Duration::from_millis(35));
+        }
+    }
+
+    #[test]
+    fn test_bad_multiplier() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(60))
+            .with_jitter(0.0);
+        for multiplier in [-1.0, 0.5, f64::NAN] {
+            assert_eq!(policy.clone().with_multiplier(multiplier).multiplier, 1.0);
+        }
+        assert_eq!(policy.clone().with_jitter(f64::NAN).jitter, 0.0);
+
+        // Set directly, nonsense is taken as one, and growth past what a
+        // Duration holds stops at the bound rather than panicking.
+        for (multiplier, second) in [
+            (-1.0, 1),
+            (f64::NAN, 1),
+            (f64::INFINITY, 60),
+            (f64::MAX, 60),
+        ] {
+            let policy = RetryPolicy {
+                multiplier,
+                jitter: f64::NAN,
+                ..policy.clone()
+            };
+            let mut retry = policy.start();
+            let delays = [retry.failed().unwrap(), retry.failed().unwrap()];
+            assert_eq!(delays, [1, second].map(Duration::from_secs));
+        }
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let policy = RetryPolicy::immediate().with_max_attempts(3);
+        let mut retry = policy.start();
+        retry.failed().unwrap();
+        retry.failed().unwrap();
+        assert!(matches!(
+            retry.failed(),
+            Err(LedgerError::RetriesExhausted { attempts: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_deadline() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(1))
+            .with_deadline(Duration::from_millis(500));
+        let mut retry = policy.start();
+        assert!(matches!(
+            retry.failed(),
+            Err(LedgerError::RetriesExhausted { attempts: 1, .. })
+        ));
+        assert!(retry.wait(Duration::from_secs(1)).unwrap() <= Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,486 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use anyhow::Context;
+use gix::Repository;
+use gix_hash::ObjectId;
+use gix_object::WriteTo;
+use rand::Rng;
+
+use crate::error::{LedgerError, LedgerResult};
+
+/// Header holding a commit's signature, whatever its format, as git does.
+const SIGNATURE_HEADER: &str = "gpgsig";
+
+/// Headers git may keep a commit's signatures in, preferred first. Both are
+/// left out of the payload a signature covers.
+const SIGNATURE_HEADERS: [&str; 2] = [SIGNATURE_HEADER, "gpgsig-sha256"];
+
+/// Namespace SSH signatures are made in, matching git's.
+const SSH_NAMESPACE: &str = "git";
+
+/// Key that signs ledger commits, through the same programs git uses. Set it
+/// on a `CommitInfo` with `with_signer`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Signer {
+    /// Sign with `ssh-keygen -Y sign` and the private key at this path, like
+    /// git with `gpg.format=ssh`.
+    Ssh(PathBuf),
+
+    /// Sign with `gpg` as `key_id`, using the keyring in `homedir` if given.
+    OpenPgp {
+        key_id: String,
+        homedir: Option<PathBuf>,
+    },
+}
+
+/// Keys whose signatures a ledger accepts on fetched commits; see
+/// `GitLedger::with_allowed_signers`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AllowedSigners {
+    /// An OpenSSH allowed signers file, as for git's
+    /// `gpg.ssh.allowedSignersFile`.
+    Ssh(PathBuf),
+
+    /// Fingerprints of OpenPGP keys, either the primary key or the signing
+    /// subkey. Public keys are looked up in `homedir` if given.
+    OpenPgp {
+        fingerprints: Vec<String>,
+        homedir: Option<PathBuf>,
+    },
+}
+
+impl Signer {
+    pub(crate) fn sign(&self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let output = match self {
+            Signer::Ssh(key) => run(
+                Command::new("ssh-keygen")
+                    .args(["-Y", "sign", "-n", SSH_NAMESPACE, "-f"])
+                    .arg(key),
+                payload,
+            )?,
+            Signer::OpenPgp { key_id, homedir } => run(
+                gpg(homedir.as_deref()).args(["--detach-sign", "--armor", "--local-user", key_id]),
+                payload,
+            )?,
+        };
+        if !output.status.success() {
+            anyhow::bail!(
+                "signing failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl AllowedSigners {
+    /// Check that `signature` over `payload` was made by an allowed key,
+    /// returning why not otherwise.
+    fn check(&self, payload: &[u8], signature: &[u8]) -> anyhow::Result<Result<(), String>> {
+        let signature_file = TempFile::new(signature)?;
+        match self {
+            AllowedSigners::Ssh(allowed) => {
+                let output = run(
+                    Command::new("ssh-keygen")
+                        .args(["-Y", "find-principals", "-f"])
+                        .arg(allowed)
+                        .arg("-s")
+                        .arg(&signature_file.0),
+                    b"",
+                )?;
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let principal = match stdout.lines().next() {
+                    Some(principal) if output.status.success() => principal.to_string(),
+                    _ => return Ok(Err("key is not an allowed signer".to_string())),
+                };
+                let output = run(
+                    Command::new("ssh-keygen")
+                        .args(["-Y", "verify", "-n", SSH_NAMESPACE, "-f"])
+                        .arg(allowed)
+                        .arg("-I")
+                        .arg(&principal)
+                        .arg("-s")
+                        .arg(&signature_file.0),
+                    payload,
+                )?;
+                if !output.status.success() {
+                    return Ok(Err(format!(
+                        "bad signature from {}: {}",
+                        principal,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )));
+                }
+                Ok(Ok(()))
+            }
+            AllowedSigners::OpenPgp {
+                fingerprints,
+                homedir,
+            } => {
+                let output = run(
+                    gpg(homedir.as_deref())
+                        .arg("--status-fd=1")
+                        .arg("--verify")
+                        .arg(&signature_file.0)
+                        .arg("-"),
+                    payload,
+                )?;
+                // VALIDSIG <fingerprint> ... <primary key fingerprint>
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let signers: Vec<_> = stdout
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+                    .flat_map(|fields| {
+                        let fields: Vec<_> = fields.split(' ').collect();
+                        [fields.first().copied(), fields.last().copied()]
+                    })
+                    .flatten()
+                    .collect();
+                if !output.status.success() || signers.is_empty() {
+                    return Ok(Err("bad or unknown OpenPGP signature".to_string()));
+                }
+                let allowed = signers.iter().any(|signer| {
+                    fingerprints.iter().any(|fingerprint| {
+                        fingerprint.replace(' ', "").eq_ignore_ascii_case(signer)
+                    })
+                });
+                if !allowed {
+                    return Ok(Err(format!("{} is not an allowed signer", signers[0])));
+                }
+                Ok(Ok(()))
+            }
+        }
+    }
+}
+
+/// Encode `commit` with a signature from `signer` over the rest of it.
+pub(crate) fn sign_commit(
+    signer: &Signer,
+    mut commit: gix_object::Commit,
+) -> anyhow::Result<gix_object::Commit> {
+    let mut payload = Vec::new();
+    commit.write_to(&mut payload)?;
+    let signature = signer.sign(&payload)?;
+    let signature = signature.strip_suffix(b"\n").unwrap_or(&signature);
+    commit
+        .extra_headers
+        .push((SIGNATURE_HEADER.into(), signature.into()));
+    Ok(commit)
+}
+
+/// Check the signature on `tip` and each of its ancestors back to, but not
+/// including, `known`.
+pub(crate) fn verify_commits(
+    repo: &Repository,
+    allowed: &AllowedSigners,
+    tip: ObjectId,
+    known: Option<ObjectId>,
+) -> LedgerResult<()> {
+    let walk = repo
+        .rev_walk([tip])
+        .selected(move |id| Some(id.to_owned()) != known)
+        .context("walk new commits")?;
+    for id in walk {
+        let id = id.context("walk new commits")?.detach();
+        verify_commit(repo, allowed, id)?;
+    }
+    Ok(())
+}
+
+fn verify_commit(repo: &Repository, allowed: &AllowedSigners, id: ObjectId) -> LedgerResult<()> {
+    let unverified = |reason: String| LedgerError::UnverifiedCommit { commit: id, reason };
+    let object = repo.find_object(id).context("find commit")?;
+    let (payload, signature) = split_signature(&object.data);
+    let signature = signature.ok_or_else(|| unverified("commit is not signed".to_string()))?;
+    allowed
+        .check(&payload, &signature)
+        .context("run signature verification")?
+        .map_err(unverified)
+}
+
+/// Split the raw bytes of a commit into the payload its signature covers,
+/// which is the commit without its signature headers, and the signature, as
+/// git does. The payload is taken from the bytes as stored rather than by
+/// encoding the parsed commit again, which need not reproduce them.
+fn split_signature(data: &[u8]) -> (Vec<u8>, Option<Vec<u8>>) {
+    let mut payload = Vec::with_capacity(data.len());
+    let mut signatures: Vec<(&str, Vec<u8>)> = Vec::new();
+    let mut in_headers = true;
+    let mut in_signature = false;
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if in_headers {
+            if line == b"\n" {
+                in_headers = false;
+            } else if let (true, Some(continued)) = (in_signature, line.strip_prefix(b" ")) {
+                if let Some((_, signature)) = signatures.last_mut() {
+                    signature.extend_from_slice(continued);
+                }
+                continue;
+            } else {
+                in_signature = false;
+                let header = SIGNATURE_HEADERS.iter().find(|header| {
+                    line.strip_prefix(header.as_bytes())
+                        .is_some_and(|rest| rest.starts_with(b" "))
+                });
+                if let Some(header) = header {
+                    signatures.push((header, line[header.len() + 1..].to_vec()));
+                    in_signature = true;
+                    continue;
+                }
+            }
+        }
+        payload.extend_from_slice(line);
+    }
+    let signature = SIGNATURE_HEADERS.iter().find_map(|wanted| {
+        signatures
+            .iter()
+            .find(|(header, _)| header == wanted)
+            .map(|(_, signature)| signature.clone())
+    });
+    (payload, signature)
+}
+
+fn gpg(homedir: Option<&Path>) -> Command {
+    let mut cmd = Command::new("gpg");
+    cmd.arg("--batch");
+    if let Some(homedir) = homedir {
+        cmd.arg("--homedir").arg(homedir);
+    }
+    cmd
+}
+
+fn run(cmd: &mut Command, stdin: &[u8]) -> anyhow::Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("spawn {:?}", cmd.get_program()))?;
+    child
+        .stdin
+        .take()
+        .context("stdin")?
+        .write_all(stdin)
+        .context("write to signing program")?;
+    Ok(child.wait_with_output()?)
+}
+
+/// A file holding a signature for the verifying program to read, removed on
+/// drop.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Create the file afresh, so nothing already in the shared temporary
+    /// directory, such as a symlink, is written through.
+    fn new(contents: &[u8]) -> anyhow::Result<TempFile> {
+        let path = std::env::temp_dir().join(format!(
+            "git-ledger-{}.sig",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let mut options = std::fs::File::options();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path).context("create signature file")?;
+        let file_path = TempFile(path);
+        file.write_all(contents).context("write signature file")?;
+        Ok(file_path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing;
+    use crate::{CommitInfo, GitLedger, Identity};
+
+    fn ssh_key(path: &Path, name: &str) -> PathBuf {
+        let key = path.join(name);
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", name, "-f"])
+            .arg(&key)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        key
+    }
+
+    fn push(ledger: &GitLedger, contents: &str) {
+        let parent = testing::tip(ledger);
+        let tree = testing::tree(&ledger.repo, contents);
+        ledger.push(parent, &tree).unwrap().committed().unwrap();
+    }
+
+    #[test]
+    fn test_ssh_signatures() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let key = ssh_key(tmp.path(), "ledger");
+        let rogue_key = ssh_key(tmp.path(), "rogue");
+        let allowed = AllowedSigners::Ssh(tmp.path().join("allowed_signers"));
+        let public = std::fs::read_to_string(key.with_extension("pub")).unwrap();
+        std::fs::write(
+            tmp.path().join("allowed_signers"),
+            format!("ledger@example.com {}", public),
+        )
+        .unwrap();
+        let info = CommitInfo::new(Identity::new("Ledger", "ledger@example.com"), "signed");
+
+        let path = tmp.path().join("a");
+        let signed =
+            testing::ledger(&path, 1).with_commit_info(info.clone().with_signer(Signer::Ssh(key)));
+        let unsigned = testing::ledger(&path, 2);
+        let reader = testing::ledger(&path, 3).with_allowed_signers(allowed.clone());
+
+        push(&signed, "1");
+        push(&signed, "2");
+        let (trusted, _tree) = reader.fetch().unwrap().unwrap();
+        let trusted = trusted.id;
+
+        // An unsigned commit is caught even once a signed one is on top.
+        push(&unsigned, "3");
+        push(&signed, "4");
+        assert!(matches!(
+            reader.fetch(),
+            Err(LedgerError::UnverifiedCommit { .. })
+        ));
+        assert_eq!(
+            reader.read_branch().unwrap().map(|(commit, _)| commit.id),
+            Some(trusted)
+        );
+
+        // So is one signed by a key not on the list.
+        let path = tmp.path().join("b");
+        let rogue =
+            testing::ledger(&path, 1).with_commit_info(info.with_signer(Signer::Ssh(rogue_key)));
+        let reader = testing::ledger(&path, 2).with_allowed_signers(allowed);
+        push(&rogue, "1");
+        assert!(matches!(
+            reader.fetch(),
+            Err(LedgerError::UnverifiedCommit { .. })
+        ));
+    }
+
+    #[test]
+    fn test_split_signature() {
+        let commit = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+gpgsig-sha256 -----BEGIN SSH SIGNATURE-----\n \
+abc\n \
+-----END SSH SIGNATURE-----\n\
+author A <a@example.com> 0 +0000\n\
+\n\
+message\n \
+indented\n";
+        let (payload, signature) = split_signature(commit);
+        assert_eq!(
+            payload,
+            b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author A <a@example.com> 0 +0000\n\
+\n\
+message\n \
+indented\n"
+        );
+        assert_eq!(
+            signature.unwrap(),
+            b"-----BEGIN SSH SIGNATURE-----\nabc\n-----END SSH SIGNATURE-----\n"
+        );
+    }
+
+    #[test]
+    fn test_signed_by_git() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let key = ssh_key(tmp.path(), "ledger");
+        let public = std::fs::read_to_string(key.with_extension("pub")).unwrap();
+        let allowed = tmp.path().join("allowed_signers");
+        std::fs::write(&allowed, format!("ledger@example.com {}", public)).unwrap();
+        let reader =
+            testing::ledger(tmp.path(), 1).with_allowed_signers(AllowedSigners::Ssh(allowed));
+
+        let git = |args: &[&str], stdin: &[u8]| {
+            let output = run(
+                Command::new("git")
+                    .arg("-C")
+                    .arg(tmp.path().join("upstream"))
+                    .args(["-c", "gpg.format=ssh", "-c"])
+                    .arg(format!("user.signingkey={}", key.display()))
+                    .args([
+                        "-c",
+                        "user.name=Ledger",
+                        "-c",
+                        "user.email=ledger@example.com",
+                    ])
+                    .args(args),
+                stdin,
+            )
+            .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        let tree = git(&["mktree"], b"");
+        let commit = git(&["commit-tree", "-S", &tree], b"no trailing newline");
+        git(&["update-ref", "refs/heads/main", &commit], b"");
+
+        let (fetched, _tree) = reader.fetch().unwrap().unwrap();
+        assert_eq!(fetched.id.to_string(), commit);
+    }
+
+    #[test]
+    fn test_openpgp_signatures() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let homedir = tmp.path().join("gnupg");
+        std::fs::create_dir(&homedir).unwrap();
+        let status = gpg(Some(&homedir))
+            .args([
+                "--passphrase",
+                "",
+                "--quick-gen-key",
+                "Ledger <ledger@example.com>",
+            ])
+            .args(["ed25519", "sign", "never"])
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let output = gpg(Some(&homedir))
+            .args(["--with-colons", "--list-keys"])
+            .output()
+            .unwrap();
+        let fingerprint = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix("fpr:::::::::"))
+            .unwrap()
+            .trim_end_matches(':')
+            .to_string();
+
+        let signed = testing::ledger(tmp.path(), 1).with_commit_info(
+            CommitInfo::default().with_signer(Signer::OpenPgp {
+                key_id: fingerprint.clone(),
+                homedir: Some(homedir.clone()),
+            }),
+        );
+        let reader = testing::ledger(tmp.path(), 2).with_allowed_signers(AllowedSigners::OpenPgp {
+            fingerprints: vec![fingerprint],
+            homedir: Some(homedir.clone()),
+        });
+        let stranger =
+            testing::ledger(tmp.path(), 3).with_allowed_signers(AllowedSigners::OpenPgp {
+                fingerprints: vec!["0000000000000000000000000000000000000000".to_string()],
+                homedir: Some(homedir),
+            });
+
+        push(&signed, "1");
+        assert!(reader.fetch().unwrap().is_some());
+        assert!(matches!(
+            stranger.fetch(),
+            Err(LedgerError::UnverifiedCommit { .. })
+        ));
+    }
+}
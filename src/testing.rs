@@ -0,0 +1,55 @@
+//! Fixtures shared by the unit tests.
+
+use std::path::Path;
+
+use gix::Repository;
+use gix_hash::ObjectId;
+use gix_object::tree::{Entry, EntryMode};
+use gix_object::Tree as TreeBuilder;
+
+use crate::GitLedger;
+
+/// The bare repository `name` under `path`, created if it does not exist yet,
+/// as a remote spec.
+pub(crate) fn upstream(path: &Path, name: &str) -> String {
+    let upstream_path = path.join(name);
+    if !upstream_path.exists() {
+        std::fs::create_dir_all(path).unwrap();
+        gix::init_bare(&upstream_path).unwrap();
+    }
+    upstream_path.to_string_lossy().to_string()
+}
+
+/// A ledger on `branch` with its local repository at `path/<local>`, pushing
+/// to `path/upstream`.
+pub(crate) fn ledger_on(path: &Path, local: &str, branch: &str) -> GitLedger {
+    GitLedger::new(
+        path.join(local),
+        upstream(path, "upstream"),
+        "origin".to_string(),
+        branch.to_string(),
+    )
+    .unwrap()
+}
+
+/// The `j`th of several ledgers on `main`, each in its own local repository,
+/// pushing to `path/upstream`.
+pub(crate) fn ledger(path: &Path, j: usize) -> GitLedger {
+    ledger_on(path, &format!("local{}", j), "main")
+}
+
+/// A tree holding `contents` in a file called `single`.
+pub(crate) fn tree(repo: &Repository, contents: &str) -> TreeBuilder {
+    let mut tb = TreeBuilder::empty();
+    tb.entries.push(Entry {
+        oid: repo.write_blob(contents).unwrap().into(),
+        mode: EntryMode::Blob,
+        filename: "single".into(),
+    });
+    tb
+}
+
+/// The upstream tip of `ledger`, fetched.
+pub(crate) fn tip(ledger: &GitLedger) -> Option<ObjectId> {
+    ledger.fetch().unwrap().map(|(commit, _)| commit.id)
+}
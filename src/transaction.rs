@@ -0,0 +1,320 @@
+use std::time::Instant;
+
+use anyhow::Context;
+use gix::{Commit, Repository};
+use gix_hash::ObjectId;
+use gix_object::Tree as TreeBuilder;
+
+use crate::error::{LedgerError, LedgerResult, PushOutcome};
+use crate::lock::lock_refs;
+use crate::observer::Observer;
+use crate::push::{push_refs, update_refs, RefPush, RefUpdate};
+use crate::GitLedger;
+
+/// Several ledgers on different branches of one local repository, pushed to
+/// the same remote, that are updated together: each push moves every branch
+/// in a single atomic push, expecting each to still be where it was fetched,
+/// so either all of them advance or none do.
+///
+/// The ledgers are fetched one after another rather than as one snapshot,
+/// but a push only lands if none of them moved since. Replicated ledgers and
+/// ledgers on a `Backend` are not supported.
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    ledgers: Vec<GitLedger>,
+}
+
+/// Result of attempting to push a transaction.
+#[must_use = "a push may lose a race, which must be handled"]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    /// Every branch now points at the new commit at the same position.
+    Committed(Vec<ObjectId>),
+
+    /// The branch of the ledger at position `ledger` was at `remote` instead
+    /// of `expected`, so nothing was written.
+    RaceLost {
+        ledger: usize,
+        expected: Option<ObjectId>,
+        remote: Option<ObjectId>,
+    },
+}
+
+impl TransactionOutcome {
+    pub fn is_committed(&self) -> bool {
+        matches!(self, TransactionOutcome::Committed(..))
+    }
+
+    /// The new commits, if the push succeeded.
+    pub fn committed(self) -> Option<Vec<ObjectId>> {
+        match self {
+            TransactionOutcome::Committed(ids) => Some(ids),
+            TransactionOutcome::RaceLost { .. } => None,
+        }
+    }
+}
+
+type State<'repo> = Option<(Commit<'repo>, gix::Tree<'repo>)>;
+
+impl Transaction {
+    /// Fails unless every ledger is kept in the same local repository with
+    /// the same upstream, each on its own branch.
+    pub fn new(ledgers: Vec<GitLedger>) -> LedgerResult<Transaction> {
+        let first = ledgers.first().ok_or_else(|| {
+            LedgerError::Repository(anyhow::anyhow!("a transaction needs a ledger"))
+        })?;
+        let path = std::fs::canonicalize(first.repo.path()).context("resolve repository")?;
+        let remote = first.single_remote("a transaction")?;
+        for (i, ledger) in ledgers.iter().enumerate().skip(1) {
+            let other = std::fs::canonicalize(ledger.repo.path()).context("resolve repository")?;
+            if other != path || ledger.single_remote("a transaction")? != remote {
+                return Err(LedgerError::Repository(anyhow::anyhow!(
+                    "ledger {} is not in the same repository with the same upstream as ledger 0",
+                    i
+                )));
+            }
+            if ledgers[..i]
+                .iter()
+                .any(|earlier| earlier.branch_ref() == ledger.branch_ref())
+            {
+                return Err(LedgerError::Repository(anyhow::anyhow!(
+                    "{} appears twice in the transaction",
+                    ledger.branch_ref()
+                )));
+            }
+        }
+        Ok(Transaction { ledgers })
+    }
+
+    pub fn ledgers(&self) -> &[GitLedger] {
+        &self.ledgers
+    }
+
+    /// Fetch every ledger, returning their states in order.
+    pub fn fetch(&self) -> LedgerResult<Vec<State<'_>>> {
+        self.ledgers.iter().map(GitLedger::fetch).collect()
+    }
+
+    /// Push a commit of each tree in `trees` on top of the commit at the same
+    /// position in `old_commit_ids`, to the ledger at that position. Each
+    /// commit is described with its ledger's `CommitInfo`.
+    pub fn push(
+        &self,
+        old_commit_ids: &[Option<ObjectId>],
+        trees: &[TreeBuilder],
+    ) -> LedgerResult<TransactionOutcome> {
+        let count = self.ledgers.len();
+        if old_commit_ids.len() != count || trees.len() != count {
+            return Err(LedgerError::Update(anyhow::anyhow!(
+                "expected {} commits and trees, got {} and {}",
+                count,
+                old_commit_ids.len(),
+                trees.len()
+            )));
+        }
+
+        let started = Instant::now();
+        for ledger in &self.ledgers {
+            ledger.observers.push_started();
+        }
+        let result = self.push_once(old_commit_ids, trees);
+        for (i, ledger) in self.ledgers.iter().enumerate() {
+            let outcome = match &result {
+                Ok(TransactionOutcome::Committed(ids)) => Ok(PushOutcome::Committed(ids[i])),
+                // Branches that had not moved report staying put.
+                Ok(TransactionOutcome::RaceLost {
+                    ledger,
+                    expected,
+                    remote,
+                }) if *ledger == i => Ok(PushOutcome::RaceLost {
+                    expected: *expected,
+                    remote: *remote,
+                }),
+                Ok(TransactionOutcome::RaceLost { .. }) => Ok(PushOutcome::RaceLost {
+                    expected: old_commit_ids[i],
+                    remote: old_commit_ids[i],
+                }),
+                Err(e) => Err(e),
+            };
+            ledger
+                .observers
+                .push_finished(started.elapsed(), outcome.as_ref().map_err(|e| *e));
+        }
+        if let Ok(TransactionOutcome::RaceLost {
+            ledger,
+            expected,
+            remote,
+        }) = result
+        {
+            self.ledgers[ledger].observers.race_lost(expected, remote);
+        }
+        result
+    }
+
+    fn push_once(
+        &self,
+        old_commit_ids: &[Option<ObjectId>],
+        trees: &[TreeBuilder],
+    ) -> LedgerResult<TransactionOutcome> {
+        let pending = self
+            .ledgers
+            .iter()
+            .zip(old_commit_ids)
+            .zip(trees)
+            .map(|((ledger, old), tree)| ledger.write_pending(*old, tree, ledger.commit_info()))
+            .collect::<LedgerResult<Vec<_>>>()?;
+        let new_commit_ids: Vec<_> = pending.iter().map(|pending| pending.id).collect();
+        self.push_commits(old_commit_ids, &new_commit_ids)
+    }
+
+    fn push_commits(
+        &self,
+        old_commit_ids: &[Option<ObjectId>],
+        new_commit_ids: &[ObjectId],
+    ) -> LedgerResult<TransactionOutcome> {
+        let first = &self.ledgers[0];
+        let updates: Vec<_> = self
+            .ledgers
+            .iter()
+            .zip(old_commit_ids.iter().zip(new_commit_ids))
+            .map(|(ledger, (old, new))| RefUpdate {
+                new: Some(*new),
+                dst_ref: ledger.branch_ref(),
+                expected: *old,
+            })
+            .collect();
+        let info = first.commit_info();
+        let pushed = match first.single_remote("a transaction")? {
+            Some(remote_name) => push_refs(&first.repo, remote_name, &updates, info),
+            None => {
+                let _lock = lock_refs(&first.repo)?;
+                update_refs(&first.repo, &updates, info)
+            }
+        };
+        let message = match pushed? {
+            RefPush::Updated => return Ok(TransactionOutcome::Committed(new_commit_ids.to_vec())),
+            RefPush::Stale => "remote branches moved during push".to_string(),
+            RefPush::Rejected(message) => message,
+        };
+
+        for (i, (ledger, old)) in self.ledgers.iter().zip(old_commit_ids).enumerate() {
+            if let Some(PushOutcome::RaceLost { expected, remote }) = ledger.maybe_raced(*old)? {
+                return Ok(TransactionOutcome::RaceLost {
+                    ledger: i,
+                    expected,
+                    remote,
+                });
+            }
+        }
+        Err(LedgerError::RemoteRejected { message })
+    }
+
+    /// Fetch every ledger, apply `f` to their states, and push the trees it
+    /// returns, one for each ledger in order.
+    pub fn update_once_with<F, E>(&self, f: F) -> LedgerResult<TransactionOutcome>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnOnce(&Repository, Vec<State<'_>>) -> std::result::Result<Vec<TreeBuilder>, E>,
+    {
+        let old = self.fetch()?;
+        let old_commit_ids: Vec<_> = old
+            .iter()
+            .map(|state| state.as_ref().map(|(commit, _)| commit.id))
+            .collect();
+        let trees = f(&self.ledgers[0].repo, old).map_err(|e| LedgerError::Update(e.into()))?;
+        self.push(&old_commit_ids, &trees)
+    }
+
+    /// Repeatedly apply `f` to the latest states until a push succeeds,
+    /// returning the new commits. Lost races are retried according to the
+    /// `RetryPolicy` of the first ledger.
+    pub fn update_with<F, E>(&self, mut f: F) -> LedgerResult<Vec<ObjectId>>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnMut(&Repository, Vec<State<'_>>) -> std::result::Result<Vec<TreeBuilder>, E>,
+    {
+        let mut retry = self.ledgers[0].retry_policy().start();
+        loop {
+            if let TransactionOutcome::Committed(ids) = self.update_once_with(&mut f)? {
+                return Ok(ids);
+            }
+            std::thread::sleep(retry.failed()?);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::{ledger_on as ledger, tip, tree};
+
+    #[test]
+    fn test_transaction() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let transaction = Transaction::new(vec![
+            ledger(tmp.path(), "local", "inventory"),
+            ledger(tmp.path(), "local", "audit"),
+        ])
+        .unwrap();
+
+        let ids = transaction
+            .update_with(|repo, states| {
+                assert!(states.iter().all(Option::is_none));
+                anyhow::Ok(vec![tree(repo, "stock"), tree(repo, "added stock")])
+            })
+            .unwrap();
+        let inventory = ledger(tmp.path(), "other", "inventory");
+        let audit = ledger(tmp.path(), "other", "audit");
+        assert_eq!(tip(&inventory), Some(ids[0]));
+        assert_eq!(tip(&audit), Some(ids[1]));
+
+        // Another writer moves one branch, so neither advances.
+        let old: Vec<_> = transaction
+            .fetch()
+            .unwrap()
+            .into_iter()
+            .map(|state| state.map(|(commit, _)| commit.id))
+            .collect();
+        let moved = audit
+            .update_with(|repo, _| anyhow::Ok(tree(repo, "unrelated")))
+            .unwrap();
+        let repo = &transaction.ledgers()[0].repo;
+        let trees = [tree(repo, "less stock"), tree(repo, "removed stock")];
+        assert_eq!(
+            transaction.push(&old, &trees).unwrap(),
+            TransactionOutcome::RaceLost {
+                ledger: 1,
+                expected: Some(ids[1]),
+                remote: Some(moved),
+            }
+        );
+        assert_eq!(tip(&inventory), Some(ids[0]));
+        assert_eq!(tip(&audit), Some(moved));
+
+        let ids = transaction
+            .update_with(|repo, states| {
+                assert_eq!(states[1].as_ref().unwrap().0.id, moved);
+                anyhow::Ok(vec![tree(repo, "less stock"), tree(repo, "removed stock")])
+            })
+            .unwrap();
+        assert_eq!(tip(&inventory), Some(ids[0]));
+        assert_eq!(tip(&audit), Some(ids[1]));
+    }
+
+    #[test]
+    fn test_transaction_ledgers() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        assert!(Transaction::new(vec![]).is_err());
+        assert!(Transaction::new(vec![
+            ledger(tmp.path(), "local1", "inventory"),
+            ledger(tmp.path(), "local2", "audit"),
+        ])
+        .is_err());
+        assert!(Transaction::new(vec![
+            ledger(tmp.path(), "local1", "inventory"),
+            ledger(tmp.path(), "local1", "inventory"),
+        ])
+        .is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::marker::PhantomData;
+
+use anyhow::Context;
+use gix::object::Kind;
+use gix::{Repository, Tree};
+use gix_hash::ObjectId;
+use gix_object::{
+    tree::{self, EntryMode},
+    Tree as TreeBuilder,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Codec, GitLedger, LedgerError, LedgerResult, PushOutcome};
+
+const VALUE_FILENAME: &str = "value";
+
+/// `GitLedger` whose state is a single serde value, stored as one blob encoded
+/// with `C`. Update functions map the old value, if any, to the new one.
+pub struct TypedLedger<T, C> {
+    inner: GitLedger,
+    codec: C,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T, C> TypedLedger<T, C>
+where
+    T: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    pub fn new(inner: GitLedger, codec: C) -> TypedLedger<T, C> {
+        TypedLedger {
+            inner,
+            codec,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn inner(&self) -> &GitLedger {
+        &self.inner
+    }
+
+    /// The latest upstream value and the commit holding it.
+    pub fn fetch(&self) -> LedgerResult<Option<(ObjectId, T)>> {
+        match self.inner.fetch()? {
+            None => Ok(None),
+            Some((commit, tree)) => Ok(Some((commit.id, self.decode(&self.inner.repo, tree)?))),
+        }
+    }
+
+    pub fn update_once_with<F, E>(&self, f: F) -> LedgerResult<PushOutcome>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnOnce(Option<T>) -> std::result::Result<T, E>,
+    {
+        self.inner
+            .update_once_with(|repo, old| self.apply(repo, old, f))
+            .map_err(LedgerError::unwrap_update)
+    }
+
+    /// Repeatedly apply `f` to the latest upstream value until a push
+    /// succeeds, returning the new commit.
+    pub fn update_with<F, E>(&self, mut f: F) -> LedgerResult<ObjectId>
+    where
+        E: Into<anyhow::Error> + std::marker::Send + std::marker::Sync + 'static,
+        F: FnMut(Option<T>) -> std::result::Result<T, E>,
+    {
+        self.inner
+            .update_with(|repo, old| self.apply(repo, old, &mut f))
+            .map_err(LedgerError::unwrap_update)
+    }
+
+    fn apply<F, E>(
+        &self,
+        repo: &Repository,
+        old: Option<(gix::Commit<'_>, Tree<'_>)>,
+        f: F,
+    ) -> LedgerResult<TreeBuilder>
+    where
+        E: Into<anyhow::Error>,
+        F: FnOnce(Option<T>) -> std::result::Result<T, E>,
+    {
+        let old = match old {
+            Some((_commit, tree)) => Some(self.decode(repo, tree)?),
+            None => None,
+        };
+        let new = f(old).map_err(|e| LedgerError::Update(e.into()))?;
+        self.encode(repo, &new)
+    }
+
+    fn decode(&self, repo: &Repository, tree: Tree<'_>) -> LedgerResult<T> {
+        let corrupt = |what: &str| LedgerError::CorruptLedger(what.to_string());
+        let tree = tree.decode().context("decode tree")?;
+        let entry = match tree.entries.as_slice() {
+            [entry] if entry.filename == VALUE_FILENAME => entry,
+            _ => return Err(corrupt("unexpected tree entries")),
+        };
+        let blob = repo.find_object(entry.oid).context("find blob")?;
+        if blob.kind != Kind::Blob {
+            return Err(corrupt("not a blob"));
+        }
+        self.codec
+            .decode(&blob.data)
+            .map_err(|e| LedgerError::CorruptLedger(format!("cannot decode value: {}", e)))
+    }
+
+    fn encode(&self, repo: &Repository, value: &T) -> LedgerResult<TreeBuilder> {
+        let data = self
+            .codec
+            .encode(value)
+            .map_err(|e| LedgerError::Update(e.context("encode value")))?;
+        let blob = repo.write_blob(data).context("write blob")?;
+        let mut tb = TreeBuilder::empty();
+        tb.entries.push(tree::Entry {
+            oid: blob.into(),
+            mode: EntryMode::Blob,
+            filename: VALUE_FILENAME.into(),
+        });
+        Ok(tb)
+    }
+}
+
+impl<T, C: Clone> Clone for TypedLedger<T, C> {
+    fn clone(&self) -> Self {
+        TypedLedger {
+            inner: self.inner.clone(),
+            codec: self.codec.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use serde::Deserialize;
+
+    use crate::testing;
+    use crate::Json;
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct Inventory {
+        items: BTreeMap<String, u64>,
+    }
+
+    fn make_ledger(path: &std::path::Path, j: usize) -> TypedLedger<Inventory, Json> {
+        TypedLedger::new(testing::ledger(path, j), Json)
+    }
+
+    #[test]
+    fn test_typed_ledger() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger1 = make_ledger(tmp.path(), 1);
+        let ledger2 = make_ledger(tmp.path(), 2);
+        assert!(ledger1.fetch().unwrap().is_none());
+
+        for (ledger, item) in [
+            (&ledger1, "apples"),
+            (&ledger2, "pears"),
+            (&ledger1, "apples"),
+        ] {
+            ledger
+                .update_with(|old| {
+                    let mut inventory = old.unwrap_or_default();
+                    *inventory.items.entry(item.to_string()).or_default() += 1;
+                    anyhow::Ok(inventory)
+                })
+                .unwrap();
+        }
+
+        let (_commit, inventory) = ledger2.fetch().unwrap().unwrap();
+        assert_eq!(inventory.items["apples"], 2);
+        assert_eq!(inventory.items["pears"], 1);
+    }
+
+    #[test]
+    fn test_corrupt_value() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let ledger = make_ledger(tmp.path(), 1);
+        let other: TypedLedger<u64, Json> = TypedLedger::new(ledger.inner().clone(), Json);
+        other.update_with(|_| anyhow::Ok(7)).unwrap();
+
+        assert!(matches!(
+            ledger.update_with(|old| anyhow::Ok(old.unwrap_or_default())),
+            Err(LedgerError::CorruptLedger(..))
+        ));
+        assert!(matches!(
+            ledger.fetch(),
+            Err(LedgerError::CorruptLedger(..))
+        ));
+    }
+}
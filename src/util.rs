@@ -1,87 +1,94 @@
+#[cfg(feature = "subprocess-push")]
 use std::ffi::OsString;
 use std::path::Path;
 
 use anyhow::Result;
+use gix::remote::Direction;
 use gix::Repository;
 use gix_hash::ObjectId;
 use gix_ref::{transaction::PreviousValue, Reference, Target};
+#[cfg(feature = "subprocess-push")]
 use once_cell::sync::OnceCell;
 
+#[cfg(feature = "subprocess-push")]
+use crate::commit::CommitInfo;
+use crate::lock::lock_init;
+
 pub fn init_repo(
     local_path: &Path,
     remote_spec: &str,
     remote_name: &str,
-    retryable: bool,
+    create_remote: bool,
 ) -> anyhow::Result<Repository> {
     log::trace!(
-        "Create/Open repository local:{} remote:{} remote_name:{} retryable:{}",
+        "Create/Open repository local:{} remote:{} remote_name:{} create_remote:{}",
         local_path.display(),
         remote_spec,
         remote_name,
-        retryable
+        create_remote
     );
-    // Gave up on trying to make this race-free. Probably not safe on untrusted
-    // dirs in /tmp either.
-    let repo = if local_path.exists() {
-        log::trace!("Opening existing repository");
-        gix::open(local_path)?
-    } else {
-        log::trace!("Initialize new bare repository with gix");
-        gix::init_bare(local_path)?
-    };
+    // Held until the remote exists, so concurrent callers neither both
+    // initialize the repository nor both add the remote.
+    let _lock = lock_init(local_path)?;
+    let repo = open_or_init_locked(local_path)?;
 
-    for attempt in 0..20 {
-        log::trace!(
-            "Waiting for remote named {}: Attempt {} / {}",
-            remote_name,
-            attempt,
-            {
-                if retryable {
-                    20
-                } else {
-                    1
-                }
-            }
-        );
-        if repo.try_find_remote(remote_name).is_some() || retryable {
-            break;
-        }
-        log::trace!(
-            "Did not find remote named {}. Sleeping 50ms and retrying.",
-            remote_name
-        );
-        std::thread::sleep(std::time::Duration::from_millis(50));
+    if repo.try_find_remote(remote_name).is_some() {
+        log::trace!("Found remote named {}", remote_name);
+        return Ok(repo);
     }
+    if !create_remote {
+        anyhow::bail!("Remote not found; unable to create");
+    }
+    log::trace!("Did not find remote named {}. Creating.", remote_name);
+    add_remote(&repo, remote_spec, remote_name)?;
 
+    // Reopen to pick up the new config.
+    let repo = gix::open(local_path)?;
     match repo.try_find_remote(remote_name) {
-        Some(..) => {
-            log::trace!("Found remote named {}", remote_name);
-            return Ok(repo);
-        }
-        None if !retryable => {
-            anyhow::bail!("Remote not found; unable to create");
-        }
-        None => {
-            log::trace!(
-                "Did not find remote named {}. Creating by shelling out to git and retrying.",
-                remote_name
-            );
-            if !git_command()
-                .current_dir(local_path)
-                .arg("remote")
-                .arg("add")
-                .arg(remote_name)
-                .arg(remote_spec)
-                .status()?
-                .success()
-            {
-                anyhow::bail!("a git command failed");
-            }
-            init_repo(local_path, remote_spec, remote_name, false)
-        }
+        Some(..) => Ok(repo),
+        None => anyhow::bail!("Remote {} missing after creating it", remote_name),
     }
 }
 
+pub fn open_or_init(local_path: &Path) -> anyhow::Result<Repository> {
+    let _lock = lock_init(local_path)?;
+    open_or_init_locked(local_path)
+}
+
+/// As `open_or_init`, with the init lock already held. Probably not safe on
+/// untrusted dirs in /tmp.
+fn open_or_init_locked(local_path: &Path) -> anyhow::Result<Repository> {
+    if local_path.exists() {
+        log::trace!("Opening existing repository");
+        Ok(gix::open(local_path)?)
+    } else {
+        log::trace!("Initialize new bare repository with gix");
+        Ok(gix::init_bare(local_path)?)
+    }
+}
+
+/// Equivalent of `git remote add`, writing the remote to the repository's
+/// config file with the default fetch refspec.
+fn add_remote(repo: &Repository, remote_spec: &str, remote_name: &str) -> Result<()> {
+    let refspec = format!("+refs/heads/*:refs/remotes/{}/*", remote_name);
+    let mut remote = repo
+        .remote_at(remote_spec)?
+        .with_refspecs(Some(refspec.as_str()), Direction::Fetch)?;
+
+    let config_path = repo.path().join("config");
+    let mut lock = gix::lock::File::acquire_to_update_resource(
+        &config_path,
+        gix::lock::acquire::Fail::Immediately,
+        None,
+    )?;
+    let mut config =
+        gix_config::File::from_path_no_includes(&config_path, gix_config::Source::Local)?;
+    remote.save_as_to(remote_name, &mut config)?;
+    config.write_to(&mut lock)?;
+    lock.commit().map_err(|e| e.error)?;
+    Ok(())
+}
+
 pub fn is_ancestor(repo: &Repository, old: ObjectId, new: ObjectId) -> Result<bool> {
     for rev in repo.rev_walk([new]).all()? {
         if rev? == old {
@@ -101,8 +108,8 @@ pub fn peeled_only(r: Option<Reference>) -> Result<Option<ObjectId>> {
     }
 }
 
-pub fn fast_forward_reference<'r>(
-    repo: &'r Repository,
+pub fn fast_forward_reference(
+    repo: &Repository,
     ref_name: &str,
     future_ref_name: &str,
 ) -> Result<bool> {
@@ -113,7 +120,7 @@ pub fn fast_forward_reference<'r>(
     fast_forward(repo, ref_name, new)
 }
 
-pub fn fast_forward<'r>(repo: &'r Repository, ref_name: &str, id: ObjectId) -> Result<bool> {
+pub fn fast_forward(repo: &Repository, ref_name: &str, id: ObjectId) -> Result<bool> {
     let cur_target = peeled_only(repo.refs.try_find(ref_name)?)?;
 
     match cur_target {
@@ -135,8 +142,10 @@ pub fn fast_forward<'r>(repo: &'r Repository, ref_name: &str, id: ObjectId) -> R
     }
 }
 
+#[cfg(feature = "subprocess-push")]
 static CELL: OnceCell<Environment> = OnceCell::new();
 
+#[cfg(feature = "subprocess-push")]
 struct Environment {
     ssh_agent_pid: Option<OsString>,
     ssh_auth_sock: Option<OsString>,
@@ -145,6 +154,7 @@ struct Environment {
     git_askpass: Option<OsString>,
 }
 
+#[cfg(feature = "subprocess-push")]
 impl Environment {
     fn new() -> Environment {
         Environment {
@@ -171,15 +181,12 @@ impl Environment {
     }
 }
 
-pub fn git_command() -> std::process::Command {
+#[cfg(feature = "subprocess-push")]
+pub fn git_command(info: &CommitInfo) -> std::process::Command {
     let environment = CELL.get_or_init(Environment::new);
     let mut cmd = std::process::Command::new("git");
-    cmd.env_clear()
-        .env("GIT_CONFIG_NOSYSTEM", "")
-        .env("GIT_COMMITTER_EMAIL", "you@example.com")
-        .env("GIT_COMMITTER_NAME", "Test User")
-        .env("GIT_AUTHOR_EMAIL", "you@example.com")
-        .env("GIT_AUTHOR_NAME", "Test User");
+    cmd.env_clear().env("GIT_CONFIG_NOSYSTEM", "");
+    info.apply(&mut cmd);
     environment.apply(&mut cmd);
     cmd.stdin(std::process::Stdio::null());
     cmd.stdout(std::process::Stdio::null());
@@ -0,0 +1,234 @@
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::Context;
+use gix_hash::ObjectId;
+
+use crate::error::LedgerResult;
+use crate::history::{History, HistoryOptions};
+use crate::util::is_ancestor;
+use crate::GitLedger;
+
+/// One new state of a ledger, seen by `GitLedger::watch`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Change {
+    /// The state this one replaced, or `None` for the first state.
+    pub old: Option<ObjectId>,
+    pub new: ObjectId,
+
+    /// The tree of `new`.
+    pub tree: ObjectId,
+}
+
+/// Iterator over changes to a ledger, polled from a background thread. Each
+/// state committed upstream is reported once, oldest first, even if several
+/// landed between two polls; after a history rewrite the rewritten tip is
+/// reported as a single change from the last state seen.
+///
+/// Failed fetches are reported as errors, and polling carries on. Iteration
+/// ends once the watch is stopped, through `stop` or a `StopHandle`, or
+/// dropped.
+pub struct Watch {
+    changes: Receiver<LedgerResult<Change>>,
+    stop: StopHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Stops a `Watch` from any thread.
+#[derive(Clone, Debug)]
+pub struct StopHandle(Sender<()>);
+
+impl StopHandle {
+    pub fn stop(&self) {
+        // The watch thread has already exited if this fails.
+        let _ = self.0.send(());
+    }
+}
+
+impl Watch {
+    pub(crate) fn new(ledger: GitLedger, interval: Duration, from: Option<ObjectId>) -> Watch {
+        let (changes_tx, changes) = channel();
+        let (stop_tx, stop_rx) = channel();
+        let thread = std::thread::spawn(move || {
+            let mut last = from;
+            loop {
+                if !poll(&ledger, &mut last, &changes_tx) {
+                    return;
+                }
+                match stop_rx.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+        Watch {
+            changes,
+            stop: StopHandle(stop_tx),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Stop polling, waiting for the watch thread to exit.
+    pub fn stop(mut self) {
+        self.stop.stop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// The next change if one has already been seen, without waiting.
+    pub fn try_next(&self) -> Option<LedgerResult<Change>> {
+        self.changes.try_recv().ok()
+    }
+}
+
+impl Iterator for Watch {
+    type Item = LedgerResult<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.changes.recv().ok()
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.stop.stop();
+    }
+}
+
+/// Fetch once, reporting any new states. Returns `false` once nobody is
+/// listening.
+fn poll(
+    ledger: &GitLedger,
+    last: &mut Option<ObjectId>,
+    changes: &Sender<LedgerResult<Change>>,
+) -> bool {
+    let tip = match ledger.fetch() {
+        Ok(state) => state.map(|(commit, _)| commit.id),
+        Err(e) => return changes.send(Err(e)).is_ok(),
+    };
+    let tip = match tip {
+        Some(tip) if Some(tip) != *last => tip,
+        _ => return true,
+    };
+
+    match changes_between(ledger, *last, tip) {
+        Ok(found) => {
+            for change in found {
+                if changes.send(Ok(change)).is_err() {
+                    return false;
+                }
+            }
+            *last = Some(tip);
+            true
+        }
+        Err(e) => changes.send(Err(e)).is_ok(),
+    }
+}
+
+/// The states from `old`, exclusive, up to `new`, oldest first.
+fn changes_between(
+    ledger: &GitLedger,
+    old: Option<ObjectId>,
+    new: ObjectId,
+) -> LedgerResult<Vec<Change>> {
+    let repo = &ledger.repo;
+    if let Some(old) = old {
+        if !is_ancestor(repo, old, new)? {
+            let tree = repo
+                .find_object(new)
+                .context("find commit")?
+                .try_into_commit()
+                .context("expected commit")?
+                .tree_id()
+                .context("commit tree")?
+                .detach();
+            return Ok(vec![Change {
+                old: Some(old),
+                new,
+                tree,
+            }]);
+        }
+    }
+
+    let mut options = HistoryOptions::default().with_first_parent_only();
+    options.stop_at_commit = old;
+    let mut states = History::new(repo, Some(new), options)?
+        .map(|entry| entry.map(|entry| (entry.id, entry.tree.id)))
+        .collect::<LedgerResult<Vec<_>>>()?;
+    states.reverse();
+
+    let mut old = old;
+    Ok(states
+        .into_iter()
+        .map(|(new, tree)| {
+            let change = Change { old, new, tree };
+            old = Some(new);
+            change
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gix_object::tree::{Entry, EntryMode};
+    use gix_object::Tree as TreeBuilder;
+
+    use crate::testing;
+
+    fn push_count(ledger: &GitLedger) -> ObjectId {
+        ledger
+            .update_with(|repo, st| {
+                let count = match st {
+                    None => 0,
+                    Some((_commit, tree)) => tree.decode()?.entries.len(),
+                };
+                let mut tb = TreeBuilder::empty();
+                for i in 0..=count {
+                    tb.entries.push(Entry {
+                        oid: repo.write_blob(i.to_string())?.into(),
+                        mode: EntryMode::Blob,
+                        filename: format!("entry{}", i).into(),
+                    });
+                }
+                anyhow::Ok(tb)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_watch() {
+        let tmp = tempdir::TempDir::new("unit.test").unwrap();
+        let writer = testing::ledger(tmp.path(), 1);
+        let watcher = testing::ledger(tmp.path(), 2);
+
+        let first = push_count(&writer);
+        let mut watch = watcher.watch(Duration::from_millis(20)).unwrap();
+        let seen = watch.next().unwrap().unwrap();
+        assert_eq!(seen.old, None);
+        assert_eq!(seen.new, first);
+
+        // Several commits between polls are reported one by one.
+        let pushed: Vec<_> = (0..3).map(|_| push_count(&writer)).collect();
+        let mut old = first;
+        for new in pushed {
+            let change = watch.next().unwrap().unwrap();
+            assert_eq!((change.old, change.new), (Some(old), new));
+            let commit = writer.repo.find_object(new).unwrap().into_commit();
+            assert_eq!(change.tree, commit.tree_id().unwrap().detach());
+            old = new;
+        }
+        assert!(watch.try_next().is_none());
+
+        let stop = watch.stop_handle();
+        std::thread::spawn(move || stop.stop());
+        assert!(watch.next().is_none());
+    }
+}